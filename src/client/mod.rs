@@ -1,6 +1,8 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
 use stun::xoraddr::XorMappedAddress;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
 
 /// Callback for receiving the mapped address.
@@ -15,6 +17,8 @@ pub struct Client {
     local_addr: SocketAddr,
     /// Task handles.
     tasks: Vec<JoinHandle<()>>,
+    /// Send a binding request immediately.
+    refresh: Arc<Notify>,
 }
 
 impl Client {
@@ -26,6 +30,11 @@ impl Client {
         self.local_addr
     }
 
+    /// Send a binding request without waiting for the next interval.
+    pub fn refresh(&self) {
+        self.refresh.notify_one()
+    }
+
     pub fn close(&self) {
         self.tasks.iter().for_each(|t| t.abort())
     }
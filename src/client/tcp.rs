@@ -3,6 +3,7 @@ use anyhow::{Error, Result};
 use std::io;
 use std::io::BufReader;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
 use stun::agent::TransactionId;
 use stun::message::{Getter, Message, BINDING_REQUEST, MAGIC_COOKIE, TRANSACTION_ID_SIZE};
@@ -11,6 +12,7 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{lookup_host, TcpSocket, TcpStream, ToSocketAddrs};
 use tokio::sync::mpsc;
 use tokio::sync::watch;
+use tokio::sync::Notify;
 use tokio::time;
 use tokio::time::{sleep, sleep_until, Instant};
 use tracing::{error, warn};
@@ -147,6 +149,8 @@ async fn worker(
     let (reset_tx, mut reset_rx) = mpsc::channel(1);
     let (stun_tx, mut stun_rx) = watch::channel(());
     let (addr_tx, mut addr_rx) = watch::channel(MappedAddress::default());
+    let refresh = Arc::new(Notify::new());
+    let refresh_rx = refresh.clone();
     let stun_handle = tokio::spawn(async move {
         let mut i = 0;
         loop {
@@ -232,6 +236,9 @@ async fn worker(
                                 );
                                 break;
                             }
+                            _ = refresh_rx.notified() => {
+                                stun.reset_immediately();
+                            }
                             _ = stun.tick() => {
                                 stun_tx.send_replace(());
                             }
@@ -271,5 +278,6 @@ async fn worker(
         name,
         local_addr,
         tasks: vec![worker_handle, stun_handle],
+        refresh,
     })
 }
@@ -3,10 +3,12 @@ use anyhow::Result;
 use hex::ToHex;
 use std::io::BufReader;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use stun::agent::TransactionId;
 use stun::message::{Getter, Message, BINDING_REQUEST};
 use stun::xoraddr::XorMappedAddress;
 use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::Notify;
 use tokio::time::{self, Duration};
 use tracing::error;
 
@@ -61,6 +63,8 @@ async fn worker(
     let sock = UdpSocket::bind(local_addr).await?;
     let local_addr = sock.local_addr()?;
     let worker_name = name.clone();
+    let refresh = Arc::new(Notify::new());
+    let refresh_rx = refresh.clone();
     let handle = tokio::spawn(async move {
         let mut buf = [0; 1024];
         let mut req: Option<TransactionId> = None;
@@ -101,6 +105,9 @@ async fn worker(
                         return;
                     }
                 }
+                _ = refresh_rx.notified() => {
+                    interval.reset_immediately();
+                }
                 _ = interval.tick() => {
                     if let Some(r) = req {
                         error!(
@@ -130,5 +137,6 @@ async fn worker(
         name,
         local_addr,
         tasks: vec![handle],
+        refresh,
     })
 }
@@ -1,72 +1,184 @@
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io;
-use std::path::Path;
-
-/// Configuration for Nat2.
-#[derive(Deserialize)]
-pub struct Config {
-    /// TCP mapping global options.
-    pub tcp: Option<Tcp>,
-    /// UDP mapping global options.
-    pub udp: Option<Udp>,
-    /// NAT Mapping List.
-    pub map: HashMap<String, Vec<Metadata>>,
-    /// Use UPnP feature. Default is true.
-    /// You can also use scheme `tcp+upnp://` or `udp+upnp://`
-    /// to enable UPnP for specific mapping.
-    pub upnp: Option<bool>,
-    /// Configuration for DNSPod provider watcher.
-    #[serde(default)]
-    pub dnspod: HashMap<String, DnsPod>,
-    /// Configuration for AliDNS provider watcher.
-    #[serde(default)]
-    pub alidns: HashMap<String, AliDNS>,
-    /// Configuration for Cloudflare provider watcher.
-    #[serde(default)]
-    pub cf: HashMap<String, Cloudflare>,
-    /// Configuration for HTTP watcher.
-    #[serde(default)]
-    pub http: HashMap<String, Http>,
-    /// Configuration for script watcher.
-    #[serde(default)]
-    pub script: HashMap<String, Script>,
+use anyhow::{anyhow, Result};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::DeserializeOwned;
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Formatter;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Invoke the macro with all watcher kinds: the doc comment of the section in `Config`,
+/// the cargo feature it depends on, the section name and the configuration type.
+/// It is the only list of the kinds; every other one is generated from it.
+macro_rules! kinds {
+    ($m:ident) => {
+        $m! {
+            /// Configuration for DNSPod provider watcher.
+            dnspod: DnsPod,
+            /// Configuration for AliDNS provider watcher.
+            alidns: AliDNS,
+            /// Configuration for Cloudflare provider watcher.
+            cf: Cloudflare,
+            /// Configuration for dynamic DNS update protocol watcher.
+            dyndns2: DynDns2,
+            /// Configuration for HTTP watcher.
+            http: Http,
+            /// Configuration for script watcher.
+            script: Script,
+            /// Configuration for Telegram bot watcher.
+            telegram: Telegram,
+            /// Configuration for Matrix room watcher.
+            matrix: Matrix,
+            /// Configuration for incoming webhook watcher.
+            webhook: Webhook,
+            /// Configuration for email watcher.
+            smtp: Smtp,
+            /// Configuration for MQTT watcher.
+            mqtt: Mqtt,
+            /// Configuration for file watcher.
+            file: File,
+            /// Configuration for qBittorrent watcher.
+            qbittorrent: QBittorrent,
+            /// Configuration for Transmission watcher.
+            transmission: Transmission,
+            /// Configuration for long-running plugin watcher.
+            plugin: Plugin,
+            /// Configuration for WebAssembly watcher.
+            #[cfg(feature = "wasm")]
+            wasm: Wasm,
+            /// Configuration for WireGuard watcher.
+            wireguard: WireGuard,
+        }
+    };
+}
+pub(crate) use kinds;
+
+/// Define `Config` and `Watchers` with a section for each watcher kind.
+macro_rules! config {
+    ($($(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])? $field:ident: $ty:ident,)*) => {
+        /// Configuration for Nat2.
+        #[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+        pub struct Config {
+            /// TCP mapping global options.
+            pub tcp: Option<Tcp>,
+            /// UDP mapping global options.
+            pub udp: Option<Udp>,
+            /// NAT Mapping List, keyed by mapping name.
+            /// It is either an object keyed by endpoint url, or a list of named mappings.
+            #[serde(deserialize_with = "deserialize_map")]
+            #[schemars(schema_with = "map_schema")]
+            pub map: HashMap<String, Mapping>,
+            /// Use UPnP feature. Default is true.
+            /// You can also use scheme `tcp+upnp://` or `udp+upnp://`
+            /// to enable UPnP for specific mapping.
+            pub upnp: Option<bool>,
+            /// Files to merge watcher definitions from.
+            /// Relative paths are resolved against the directory of this file.
+            #[serde(default)]
+            pub include: Vec<String>,
+            $(
+                $(#[doc = $doc])*
+                $(#[cfg($cfg)])?
+                #[serde(default)]
+                pub $field: HashMap<String, $ty>,
+            )*
+        }
+
+        /// Watcher definitions of an included file, keyed by watcher name.
+        /// The fields are the same as the watcher sections of `Config`.
+        #[derive(Default, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        struct Watchers {
+            /// Files to merge watcher definitions from.
+            /// Relative paths are resolved against the directory of the included file.
+            include: Vec<String>,
+            $($(#[cfg($cfg)])? $field: HashMap<String, $ty>,)*
+        }
+    };
 }
 
+kinds!(config);
+
 /// Configuration for DNSPod provider.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
 pub struct DnsPod {
     /// Similar to username.
+    #[serde(default)]
     pub secret_id: String,
+    /// Read `secret_id` from this file instead.
+    pub secret_id_file: Option<String>,
     /// Similar to password.
+    #[serde(default)]
     pub secret_key: String,
+    /// Read `secret_key` from this file instead.
+    pub secret_key_file: Option<String>,
 }
 
 /// Configuration for AliDNS provider.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
 pub struct AliDNS {
     /// The request URL may vary by region.
     pub url: Option<String>,
     /// Similar to username.
+    #[serde(default)]
     pub secret_id: String,
+    /// Read `secret_id` from this file instead.
+    pub secret_id_file: Option<String>,
     /// Similar to password.
+    #[serde(default)]
     pub secret_key: String,
+    /// Read `secret_key` from this file instead.
+    pub secret_key_file: Option<String>,
 }
 
 /// Configuration for Cloudflare provider.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
 pub struct Cloudflare {
     /// API token.
+    #[serde(default)]
     pub token: String,
+    /// Read `token` from this file instead.
+    pub token_file: Option<String>,
+    /// Zone id of the domain.
+    /// This field disables zone lookup, so the token doesn't need the Zone:Read permission.
+    pub zone_id: Option<String>,
+    /// Delete the duplicates of a record matched by comment or tags, keeping the first one.
+    /// Deleted records can't be restored, so don't enable it if several mappings share
+    /// the same record name, type, comment and tags on purpose.
+    #[serde(default)]
+    pub cleanup: bool,
+}
+
+/// Configuration for dynamic DNS update protocol (dyndns2).
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct DynDns2 {
+    /// Update url, e.g. `https://dynupdate.no-ip.com/nic/update`.
+    pub url: String,
+    /// Similar to username.
+    #[serde(default)]
+    pub username: String,
+    /// Read `username` from this file instead.
+    pub username_file: Option<String>,
+    /// Similar to password.
+    #[serde(default)]
+    pub password: String,
+    /// Read `password` from this file instead.
+    pub password_file: Option<String>,
 }
 
 /// Configuration for HTTP API.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
 pub struct Http {
     /// Request url could contain placeholder `{ip}` and `{port}` which
     /// will be replaced with real value before sending the request.
+    #[serde(default)]
     pub url: String,
+    /// Read `url` from this file instead.
+    pub url_file: Option<String>,
     /// Request method.
     pub method: String,
     /// Request body could be JSON string, plain text, etc...
@@ -76,10 +188,12 @@ pub struct Http {
     /// For example, `Content-Type` should be set based on the content in the `body`.
     #[serde(default)]
     pub headers: HashMap<String, String>,
+    /// Read more headers from this file, one `Name: value` per line.
+    pub headers_file: Option<String>,
 }
 
 /// Configuration for script.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
 pub struct Script {
     /// Path to executable file.
     pub path: String,
@@ -88,10 +202,418 @@ pub struct Script {
     /// will be passed to the program as the last argument.
     #[serde(default)]
     pub args: Vec<String>,
+    /// Working directory of the program.
+    pub dir: Option<String>,
+    /// Extra environment variables.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// The amount of time in seconds to wait before killing the program.
+    pub timeout: Option<u64>,
+    /// Exit codes which indicate a temporary failure, e.g. `[75]`.
+    /// If not empty, other non-zero exit codes are treated as permanent
+    /// failures and not retried until the mapped address changes again.
+    #[serde(default)]
+    pub retry_codes: Vec<i32>,
+}
+
+/// Configuration for long-running plugin.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct Plugin {
+    /// Path to executable file.
+    pub path: String,
+    /// Arguments to pass to the program.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Working directory of the program.
+    pub dir: Option<String>,
+    /// Extra environment variables.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// The amount of time in seconds to wait for a response. Default is 30.
+    /// The program is restarted if it does not respond in time.
+    pub timeout: Option<u64>,
+}
+
+/// Configuration for WebAssembly component.
+#[cfg(feature = "wasm")]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct Wasm {
+    /// Path to component file.
+    pub path: String,
+    /// Origins which the component is allowed to send HTTP requests to, including redirects,
+    /// e.g. `https://api.example.com` or `http://*.example.com:8080`.
+    /// The scheme defaults to `https`, and the port defaults to the one of the scheme.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// The amount of time in seconds to wait for a call to finish. Default is 30.
+    pub timeout: Option<u64>,
+    /// Maximum size in MiB of the component memory. Default is 64.
+    pub memory: Option<usize>,
+}
+
+/// Configuration for Telegram bot.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct Telegram {
+    /// Bot API server url. Default is `https://api.telegram.org`.
+    pub url: Option<String>,
+    /// Bot token.
+    #[serde(default)]
+    pub token: String,
+    /// Read `token` from this file instead.
+    pub token_file: Option<String>,
+    /// Unique identifier for the target chat or username of the target channel.
+    pub chat_id: String,
+    /// Message template. Placeholder `{mapper}`, `{protocol}`, `{ip}`, `{port}`,
+    /// `{old_ip}` and `{old_port}` are supported.
+    /// Note that this value could be overridden by watcher metadata.
+    pub message: Option<String>,
+}
+
+/// Configuration for Matrix room.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct Matrix {
+    /// Homeserver url.
+    pub url: String,
+    /// Access token of the sender.
+    #[serde(default)]
+    pub token: String,
+    /// Read `token` from this file instead.
+    pub token_file: Option<String>,
+    /// Room id, e.g. `!abc:matrix.org`.
+    pub room_id: String,
+    /// Message template. Placeholder `{mapper}`, `{protocol}`, `{ip}`, `{port}`,
+    /// `{old_ip}` and `{old_port}` are supported.
+    /// Note that this value could be overridden by watcher metadata.
+    pub message: Option<String>,
+}
+
+/// Configuration for incoming webhook.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct Webhook {
+    /// Webhook url.
+    #[serde(default)]
+    pub url: String,
+    /// Read `url` from this file instead.
+    pub url_file: Option<String>,
+    /// Payload format, `slack` or `discord`. Default is `slack`.
+    pub format: Option<String>,
+    /// Message template. Placeholder `{mapper}`, `{protocol}`, `{ip}`, `{port}`,
+    /// `{old_ip}` and `{old_port}` are supported.
+    /// Note that this value could be overridden by watcher metadata.
+    pub message: Option<String>,
+}
+
+/// Configuration for SMTP server.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct Smtp {
+    /// SMTP server host.
+    pub host: String,
+    /// SMTP server port. Default is 587 for `starttls`, 465 for `tls` and 25 for `none`.
+    pub port: Option<u16>,
+    /// Connection security, `starttls`, `tls` or `none`. Default is `starttls`.
+    pub tls: Option<String>,
+    /// Similar to username.
+    pub username: Option<String>,
+    /// Read `username` from this file instead.
+    pub username_file: Option<String>,
+    /// Similar to password.
+    pub password: Option<String>,
+    /// Read `password` from this file instead.
+    pub password_file: Option<String>,
+    /// Sender address, e.g. `nat2 <nat2@example.com>`.
+    pub from: String,
+    /// Recipient addresses.
+    pub to: Vec<String>,
+    /// Subject template. Placeholder `{mapper}`, `{protocol}`, `{ip}`, `{port}`,
+    /// `{old_ip}` and `{old_port}` are supported.
+    pub subject: Option<String>,
+    /// Body template. Placeholders are the same as `subject`.
+    /// Note that this value could be overridden by watcher metadata.
+    pub body: Option<String>,
+}
+
+/// Configuration for MQTT broker.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct Mqtt {
+    /// Broker url, e.g. `mqtt://127.0.0.1:1883` or `mqtts://127.0.0.1:8883`.
+    pub url: String,
+    /// Client id. Default is `nat2-{hostname}`.
+    pub client_id: Option<String>,
+    /// Similar to username.
+    pub username: Option<String>,
+    /// Read `username` from this file instead.
+    pub username_file: Option<String>,
+    /// Similar to password.
+    pub password: Option<String>,
+    /// Read `password` from this file instead.
+    pub password_file: Option<String>,
+    /// Topic template. Default is `nat2/{mapper}`, where characters other than letters, digits,
+    /// `-`, `_` and `.` in the mapper name are replaced with `_`.
+    /// Placeholder `{mapper}`, `{protocol}`, `{ip}`, `{port}`, `{old_ip}` and `{old_port}` are supported.
+    pub topic: Option<String>,
+    /// Quality of service, 0, 1 or 2. Default is 1.
+    /// With 1 or 2, the address is only reported as published once the broker acknowledges it.
+    pub qos: Option<u8>,
+    /// Whether the message is retained by the broker. Default is true.
+    pub retain: Option<bool>,
+    /// Topic of the retained `online` message, and the last will `offline` message
+    /// which is published by the broker when nat2 is disconnected unexpectedly.
+    pub status_topic: Option<String>,
+}
+
+/// Configuration for template file.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct File {
+    /// Path to template file.
+    /// Placeholder `{mapper}`, `{protocol}`, `{ip}`, `{port}`, `{old_ip}` and `{old_port}` are supported.
+    /// If the `value` field in watcher metadata is not empty, it is used as the template instead.
+    pub template: Option<String>,
+    /// Path to target file. The file is replaced atomically.
+    pub path: String,
+    /// Command to run after the file is written, e.g. `["nginx", "-s", "reload"]`.
+    #[serde(default)]
+    pub command: Vec<String>,
+}
+
+/// Configuration for qBittorrent WebUI.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct QBittorrent {
+    /// WebUI url, e.g. `http://127.0.0.1:8080`.
+    pub url: String,
+    /// Similar to username.
+    pub username: Option<String>,
+    /// Read `username` from this file instead.
+    pub username_file: Option<String>,
+    /// Similar to password.
+    pub password: Option<String>,
+    /// Read `password` from this file instead.
+    pub password_file: Option<String>,
+    /// Whether to set the announce ip to the mapped ip. Default is false.
+    #[serde(default)]
+    pub announce_ip: bool,
+}
+
+/// Configuration for Transmission RPC.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct Transmission {
+    /// RPC url, e.g. `http://127.0.0.1:9091/transmission/rpc`.
+    pub url: String,
+    /// Similar to username.
+    pub username: Option<String>,
+    /// Read `username` from this file instead.
+    pub username_file: Option<String>,
+    /// Similar to password.
+    pub password: Option<String>,
+    /// Read `password` from this file instead.
+    pub password_file: Option<String>,
+}
+
+/// Configuration for WireGuard watcher.
+/// The mapped address is pushed to the remote nat2, which sets it as the peer endpoint
+/// in its own interface.
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct WireGuard {
+    /// Url of the remote nat2 to push the mapped address to, e.g. `http://203.0.113.1:51821`.
+    pub remote: Option<String>,
+    /// Accept the endpoints pushed by the remote nat2 on this address, e.g. `0.0.0.0:51821`.
+    pub listen: Option<String>,
+    /// Local interface whose peers are updated with the pushed endpoints, e.g. `wg0`.
+    pub interface: Option<String>,
+    /// Shared secret of both sites to sign the pushed endpoints.
+    #[serde(default)]
+    pub token: String,
+    /// Read `token` from this file instead.
+    pub token_file: Option<String>,
+}
+
+/// A NAT mapping.
+/// In the url-keyed form, it is either a watcher list, or an object with the watcher list
+/// and mapping options.
+#[derive(Clone, Default, PartialEq)]
+pub struct Mapping {
+    /// Local endpoint url of the url-keyed form, e.g. `tcp://0.0.0.0:6666`.
+    pub url: Option<String>,
+    /// `tcp` or `udp`.
+    pub protocol: Option<String>,
+    /// Socket binding address of the NAT client.
+    /// If UPnP is used, only the IP address is used and the port is the mapped external port.
+    pub listen: Option<String>,
+    /// Address of the local service that the gateway forwards traffics to. It requires UPnP.
+    pub forward: Option<String>,
+    /// Use UPnP feature. Default is the global option.
+    pub upnp: Option<bool>,
+    /// Watcher list.
+    pub watchers: Vec<Metadata>,
+    /// TCP mapping options, which are merged over the global options.
+    pub tcp: Option<Tcp>,
+    /// UDP mapping options, which are merged over the global options.
+    pub udp: Option<Udp>,
+}
+
+/// A mapping object in the url-keyed form.
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct MappingObject {
+    /// Watcher list.
+    #[serde(default)]
+    watchers: Vec<Metadata>,
+    /// TCP mapping options, which are merged over the global options.
+    tcp: Option<Tcp>,
+    /// UDP mapping options, which are merged over the global options.
+    udp: Option<Udp>,
+}
+
+/// A mapping in the list form.
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NamedMapping {
+    /// Unique mapping name.
+    pub name: String,
+    /// `tcp` or `udp`.
+    #[schemars(extend("enum" = ["tcp", "udp"]))]
+    protocol: String,
+    /// Socket binding address of the NAT client.
+    /// If UPnP is used, only the IP address is used and the port is the mapped external port.
+    listen: Option<String>,
+    /// Address of the local service that the gateway forwards traffics to. It requires UPnP.
+    forward: Option<String>,
+    /// Use UPnP feature. Default is the global option.
+    upnp: Option<bool>,
+    /// Watcher list.
+    #[serde(default)]
+    watchers: Vec<Metadata>,
+    /// TCP mapping options, which are merged over the global options.
+    tcp: Option<Tcp>,
+    /// UDP mapping options, which are merged over the global options.
+    udp: Option<Udp>,
+}
+
+impl From<NamedMapping> for Mapping {
+    fn from(v: NamedMapping) -> Self {
+        Mapping {
+            url: None,
+            protocol: Some(v.protocol),
+            listen: v.listen,
+            forward: v.forward,
+            upnp: v.upnp,
+            watchers: v.watchers,
+            tcp: v.tcp,
+            udp: v.udp,
+        }
+    }
+}
+
+impl JsonSchema for Mapping {
+    fn schema_name() -> Cow<'static, str> {
+        "Mapping".into()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "A watcher list, or an object with the watcher list and mapping options.",
+            "anyOf": [
+                generator.subschema_for::<Vec<Metadata>>(),
+                generator.subschema_for::<MappingObject>(),
+            ]
+        })
+    }
+}
+
+/// Schema of the mapping list, which is either keyed by url or a list of named mappings.
+fn map_schema(generator: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "anyOf": [
+            {
+                "type": "object",
+                "propertyNames": {
+                    "pattern": "^(tcp|udp|tcp\\+upnp|upnp\\+tcp|udp\\+upnp|upnp\\+udp)://"
+                },
+                "additionalProperties": generator.subschema_for::<Mapping>(),
+            },
+            {
+                "type": "array",
+                "items": generator.subschema_for::<NamedMapping>(),
+            }
+        ]
+    })
+}
+
+impl<'de> Deserialize<'de> for Mapping {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MappingVisitor;
+
+        impl<'de> Visitor<'de> for MappingVisitor {
+            type Value = Mapping;
+
+            fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+                f.write_str("a watcher list or a mapping object")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Mapping, A::Error> {
+                let watchers = Vec::deserialize(SeqAccessDeserializer::new(seq))?;
+                Ok(Mapping {
+                    watchers,
+                    ..Default::default()
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Mapping, A::Error> {
+                let v = MappingObject::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(Mapping {
+                    watchers: v.watchers,
+                    tcp: v.tcp,
+                    udp: v.udp,
+                    ..Default::default()
+                })
+            }
+        }
+
+        deserializer.deserialize_any(MappingVisitor)
+    }
+}
+
+/// Deserialize the mapping list from either the url-keyed form or the list form.
+fn deserialize_map<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, Mapping>, D::Error> {
+    struct MapVisitor;
+
+    impl<'de> Visitor<'de> for MapVisitor {
+        type Value = HashMap<String, Mapping>;
+
+        fn expecting(&self, f: &mut Formatter) -> std::fmt::Result {
+            f.write_str("a mapping object keyed by url or a mapping list")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            let mut mappings: HashMap<String, Mapping> =
+                HashMap::deserialize(MapAccessDeserializer::new(map))?;
+            for (key, value) in mappings.iter_mut() {
+                value.url = Some(key.clone());
+            }
+            Ok(mappings)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut mappings = HashMap::new();
+            while let Some(v) = seq.next_element::<NamedMapping>()? {
+                if mappings.contains_key(&v.name) {
+                    return Err(de::Error::custom(format!(
+                        "duplicate mapping name `{}`",
+                        v.name
+                    )));
+                }
+                mappings.insert(v.name.clone(), v.into());
+            }
+            Ok(mappings)
+        }
+    }
+
+    deserializer.deserialize_any(MapVisitor)
 }
 
 /// TCP mapping global options.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
 pub struct Tcp {
     /// TCP STUN server address:port pairs.
     /// The server must support STUN over TCP protocol.
@@ -107,7 +629,7 @@ pub struct Tcp {
 }
 
 /// UDP mapping global options.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq)]
 pub struct Udp {
     /// UDP STUN server address:port pairs.
     /// It selects hosts based on round-robin ordering.
@@ -116,10 +638,58 @@ pub struct Udp {
     pub interval: Option<u64>,
 }
 
+impl Tcp {
+    /// Merge the options over the global options.
+    fn merge(&self, global: &Tcp) -> Tcp {
+        Tcp {
+            stun: self.stun.clone().or(global.stun.clone()),
+            keepalive: self.keepalive.clone().or(global.keepalive.clone()),
+            interval: self.interval.or(global.interval),
+            stun_interval: self.stun_interval.or(global.stun_interval),
+        }
+    }
+}
+
+impl Udp {
+    /// Merge the options over the global options.
+    fn merge(&self, global: &Udp) -> Udp {
+        Udp {
+            stun: self.stun.clone().or(global.stun.clone()),
+            interval: self.interval.or(global.interval),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the TCP options of the mapping.
+    pub fn tcp_options(&self, key: &str) -> Option<Tcp> {
+        match (self.map.get(key).and_then(|v| v.tcp.as_ref()), &self.tcp) {
+            (Some(opt), Some(global)) => Some(opt.merge(global)),
+            (opt, global) => opt.or(global.as_ref()).cloned(),
+        }
+    }
+
+    /// Returns the UDP options of the mapping.
+    pub fn udp_options(&self, key: &str) -> Option<Udp> {
+        match (self.map.get(key).and_then(|v| v.udp.as_ref()), &self.udp) {
+            (Some(opt), Some(global)) => Some(opt.merge(global)),
+            (opt, global) => opt.or(global.as_ref()).cloned(),
+        }
+    }
+}
+
 /// Metadata of watcher.
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize, JsonSchema, PartialEq, Serialize)]
+#[schemars(extend(
+    "if" = {
+        "properties": {"type": {"pattern": "^([Ss][Vv][Cc][Bb]|[Hh][Tt][Tt][Pp][Ss]|[Mm][Xx])$"}},
+        "required": ["type"]
+    },
+    "then" = {"required": ["priority"]}
+))]
 pub struct Metadata {
     /// Name of the watcher defined in the watcher list.
+    /// It can be qualified by the watcher kind, e.g. `cf:main`.
     pub name: String,
     /// Value could contain placeholder `{ip}` and `{port}` which
     /// will be replaced with real value in the watcher.
@@ -139,10 +709,690 @@ pub struct Metadata {
     pub ttl: Option<u32>,
     /// Whether the record is proxied by Cloudflare.
     pub proxied: Option<bool>,
+    /// Record line (e.g. `电信`, `联通`, `境外`) of the DNS record.
+    /// Only the record on the same line is updated.
+    pub line: Option<String>,
+    /// Weight of the DNS record.
+    pub weight: Option<u32>,
+    /// Whether the DNS record is enabled.
+    pub enabled: Option<bool>,
+    /// Comment of the Cloudflare record.
+    /// Only the record with the same comment is updated.
+    pub comment: Option<String>,
+    /// Tags of the Cloudflare record in `name:value` format.
+    /// Only the record with all of these tags is updated.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Public key of the local WireGuard interface, which is a peer of the remote interface.
+    pub peer: Option<String>,
+}
+
+impl Metadata {
+    /// Returns the kind qualifier and the name of the watcher.
+    pub fn watcher(&self) -> (Option<&str>, &str) {
+        match self.name.split_once(':') {
+            Some((kind, name)) => (Some(kind), name),
+            None => (None, self.name.as_str()),
+        }
+    }
 }
 
-pub(crate) fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
-    let f = File::open(path)?;
-    let cfg = serde_json::from_reader(f)?;
+/// Credentials which can be read from environment variables and files.
+trait Secrets {
+    /// Expand the environment variables and read the `*_file` variants.
+    /// Kinds without credentials have nothing to resolve.
+    fn resolve_secrets(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Replace `${ENV_VAR}` with the value of the environment variable.
+/// `$${` is an escape of a literal `${`.
+fn expand_env(value: &str) -> Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(v) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = v;
+        } else if rest.starts_with("${") {
+            let end = rest.find('}').ok_or(anyhow!(
+                "unclosed `${{` in `{value}`, use `$${{` for a literal `${{`"
+            ))?;
+            let name = &rest[2..end];
+            let var = env::var(name).map_err(|e| anyhow!("environment variable `{name}`: {e}"))?;
+            result.push_str(&var);
+            rest = &rest[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Returns the credential from either the value or the file.
+/// Trailing whitespaces in the file are removed.
+fn secret(field: &str, value: Option<&str>, file: Option<&str>) -> Result<Option<String>> {
+    match (value, file) {
+        (Some(_), Some(_)) => Err(anyhow!(
+            "`{field}` and `{field}_file` are mutually exclusive"
+        )),
+        (Some(value), None) => expand_env(value).map(Some),
+        (None, Some(path)) => {
+            let path = expand_env(path)?;
+            let value =
+                fs::read_to_string(&path).map_err(|e| anyhow!("{field}_file `{path}`: {e}"))?;
+            Ok(Some(value.trim_end().to_string()))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Resolve a required credential. An empty value is treated as missing.
+fn required(field: &str, value: &mut String, file: &Option<String>) -> Result<()> {
+    let v = Some(value.as_str()).filter(|v| !v.is_empty());
+    *value = secret(field, v, file.as_deref())?.ok_or(anyhow!("missing field `{field}`"))?;
+    Ok(())
+}
+
+/// Resolve an optional credential.
+fn optional(field: &str, value: &mut Option<String>, file: &Option<String>) -> Result<()> {
+    *value = secret(field, value.as_deref(), file.as_deref())?;
+    Ok(())
+}
+
+impl Secrets for DnsPod {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        required("secret_id", &mut self.secret_id, &self.secret_id_file)?;
+        required("secret_key", &mut self.secret_key, &self.secret_key_file)
+    }
+}
+
+impl Secrets for AliDNS {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        required("secret_id", &mut self.secret_id, &self.secret_id_file)?;
+        required("secret_key", &mut self.secret_key, &self.secret_key_file)
+    }
+}
+
+impl Secrets for Cloudflare {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        required("token", &mut self.token, &self.token_file)
+    }
+}
+
+impl Secrets for DynDns2 {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        required("username", &mut self.username, &self.username_file)?;
+        required("password", &mut self.password, &self.password_file)
+    }
+}
+
+impl Secrets for Http {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        required("url", &mut self.url, &self.url_file)?;
+        for value in self.headers.values_mut() {
+            *value = expand_env(value)?;
+        }
+        if let Some(path) = &self.headers_file {
+            let path = expand_env(path)?;
+            let content =
+                fs::read_to_string(&path).map_err(|e| anyhow!("headers_file `{path}`: {e}"))?;
+            for line in content.lines().filter(|v| !v.trim().is_empty()) {
+                let (name, value) = line
+                    .split_once(':')
+                    .ok_or(anyhow!("invalid header `{line}` in headers_file `{path}`"))?;
+                self.headers
+                    .insert(name.trim().to_string(), value.trim().to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Secrets for Telegram {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        required("token", &mut self.token, &self.token_file)
+    }
+}
+
+impl Secrets for Matrix {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        required("token", &mut self.token, &self.token_file)
+    }
+}
+
+impl Secrets for Webhook {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        required("url", &mut self.url, &self.url_file)
+    }
+}
+
+impl Secrets for Smtp {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        optional("username", &mut self.username, &self.username_file)?;
+        optional("password", &mut self.password, &self.password_file)
+    }
+}
+
+impl Secrets for Mqtt {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        optional("username", &mut self.username, &self.username_file)?;
+        optional("password", &mut self.password, &self.password_file)
+    }
+}
+
+impl Secrets for WireGuard {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        required("token", &mut self.token, &self.token_file)
+    }
+}
+
+impl Secrets for QBittorrent {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        optional("username", &mut self.username, &self.username_file)?;
+        optional("password", &mut self.password, &self.password_file)
+    }
+}
+
+impl Secrets for Transmission {
+    fn resolve_secrets(&mut self) -> Result<()> {
+        optional("username", &mut self.username, &self.username_file)?;
+        optional("password", &mut self.password, &self.password_file)
+    }
+}
+
+impl Secrets for Script {}
+
+impl Secrets for File {}
+
+impl Secrets for Plugin {}
+
+#[cfg(feature = "wasm")]
+impl Secrets for Wasm {}
+
+impl Config {
+    /// Move the watcher sections out of the configuration.
+    fn take_watchers(&mut self) -> Watchers {
+        macro_rules! take {
+            ($($(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])? $field:ident: $ty:ident,)*) => {
+                Watchers {
+                    include: Vec::new(),
+                    $($(#[cfg($cfg)])? $field: std::mem::take(&mut self.$field),)*
+                }
+            };
+        }
+        kinds!(take)
+    }
+
+    /// Put the watcher sections back into the configuration.
+    fn set_watchers(&mut self, watchers: Watchers) {
+        macro_rules! set {
+            ($($(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])? $field:ident: $ty:ident,)*) => {
+                $($(#[cfg($cfg)])? {
+                    self.$field = watchers.$field;
+                })*
+            };
+        }
+        kinds!(set);
+    }
+}
+
+impl Watchers {
+    /// Returns the kind and the name of all watchers, e.g. `("cf", "main")`.
+    fn names(&self) -> Vec<(&'static str, &str)> {
+        let mut names = Vec::new();
+        macro_rules! names {
+            ($($(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])? $field:ident: $ty:ident,)*) => {
+                $(
+                    $(#[cfg($cfg)])?
+                    names.extend(self.$field.keys().map(|v| (stringify!($field), v.as_str())));
+                )*
+            };
+        }
+        kinds!(names);
+        names
+    }
+
+    /// Move the watchers of another file into this one.
+    fn merge(&mut self, other: Watchers) {
+        macro_rules! merge {
+            ($($(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])? $field:ident: $ty:ident,)*) => {
+                $($(#[cfg($cfg)])? self.$field.extend(other.$field);)*
+            };
+        }
+        kinds!(merge);
+    }
+
+    /// Resolve the credentials of all watchers.
+    /// Returns all of the errors found.
+    fn resolve_secrets(&mut self) -> Vec<anyhow::Error> {
+        fn resolve<T: Secrets>(
+            kind: &str,
+            watchers: &mut HashMap<String, T>,
+            errors: &mut Vec<anyhow::Error>,
+        ) {
+            for (name, watcher) in watchers.iter_mut() {
+                if let Err(e) = watcher.resolve_secrets() {
+                    errors.push(anyhow!("{e} in {kind}.{name}"));
+                }
+            }
+        }
+        let mut errors = Vec::new();
+        macro_rules! resolve {
+            ($($(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])? $field:ident: $ty:ident,)*) => {
+                $($(#[cfg($cfg)])? resolve(stringify!($field), &mut self.$field, &mut errors);)*
+            };
+        }
+        kinds!(resolve);
+        errors
+    }
+}
+
+/// Parse a configuration file.
+/// The format is detected by file extension, `.yaml`, `.yml`, `.toml` or `.json`.
+/// Other extensions are treated as JSON.
+fn parse<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let s = fs::read_to_string(path).map_err(|e| anyhow!("{}: {e}", path.display()))?;
+    let v = match path.extension().and_then(|v| v.to_str()) {
+        Some("yaml" | "yml") => serde_norway::from_str(&s).map_err(|e| anyhow!(e)),
+        Some("toml") => toml::from_str(&s).map_err(|e| anyhow!(e)),
+        _ => serde_json::from_str(&s).map_err(|e| anyhow!(e)),
+    };
+    v.map_err(|e| anyhow!("{}: {}", path.display(), e.to_string().trim_end()))
+}
+
+/// Record where the watchers of a file are defined.
+/// Watcher names must be unique across all kinds and files, since metadata refers to them by name.
+fn define(
+    defined: &mut HashMap<String, (&'static str, PathBuf)>,
+    watchers: &Watchers,
+    path: &Path,
+    errors: &mut Vec<String>,
+) {
+    for (kind, name) in watchers.names() {
+        if name.contains(':') {
+            errors.push(format!(
+                "{}: invalid watcher name `{name}` in {kind}, `:` is reserved for the kind qualifier",
+                path.display()
+            ));
+        } else if let Some((other_kind, other)) = defined.get(name) {
+            errors.push(format!(
+                "{}: duplicate watcher name `{name}` in {kind}, already defined in {other_kind} of {}",
+                path.display(),
+                other.display()
+            ));
+        } else {
+            defined.insert(name.to_string(), (kind, path.to_path_buf()));
+        }
+    }
+}
+
+/// Load the configuration file, merging the watchers of the included files.
+pub(crate) fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+    let path = path.as_ref();
+    let mut cfg: Config = parse(path)?;
+    let mut watchers = cfg.take_watchers();
+    let mut errors: Vec<String> = watchers
+        .resolve_secrets()
+        .iter()
+        .map(|e| format!("{}: {e}", path.display()))
+        .collect();
+    // Files where the watchers are defined.
+    let mut defined = HashMap::new();
+    define(&mut defined, &watchers, path, &mut errors);
+    // Files to include, in reverse order. Each is resolved against the directory of its including file.
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut pending: Vec<PathBuf> = cfg.include.iter().rev().map(|v| dir.join(v)).collect();
+    let mut seen = HashSet::from([fs::canonicalize(path).unwrap_or(path.to_path_buf())]);
+    while let Some(include) = pending.pop() {
+        // A file is merged only once, even if it is included again or in a cycle.
+        if !seen.insert(fs::canonicalize(&include).unwrap_or(include.clone())) {
+            continue;
+        }
+        let mut included: Watchers = match parse(&include) {
+            Ok(v) => v,
+            Err(e) => {
+                errors.push(e.to_string());
+                continue;
+            }
+        };
+        let dir = include.parent().unwrap_or(Path::new(""));
+        pending.extend(included.include.iter().rev().map(|v| dir.join(v)));
+        for e in included.resolve_secrets() {
+            errors.push(format!("{}: {e}", include.display()));
+        }
+        define(&mut defined, &included, &include, &mut errors);
+        watchers.merge(included);
+    }
+    if !errors.is_empty() {
+        return Err(anyhow!(errors.join("\n")));
+    }
+    cfg.set_watchers(watchers);
     Ok(cfg)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock;
+
+    #[test]
+    fn expand_env_escape() {
+        env::set_var("NAT2_TEST_TOKEN", "secret");
+        assert_eq!(
+            expand_env("Bearer ${NAT2_TEST_TOKEN}").unwrap(),
+            "Bearer secret"
+        );
+        assert_eq!(
+            expand_env("$${NAT2_TEST_TOKEN}").unwrap(),
+            "${NAT2_TEST_TOKEN}"
+        );
+        assert_eq!(
+            expand_env("$$${NAT2_TEST_TOKEN}").unwrap(),
+            "$${NAT2_TEST_TOKEN}"
+        );
+        assert_eq!(expand_env("$5 {ip} $").unwrap(), "$5 {ip} $");
+        assert!(expand_env("${NAT2_TEST_TOKEN").is_err());
+        assert!(expand_env("${NAT2_TEST_UNDEFINED}").is_err());
+    }
+
+    /// Returns a directory with the files, which is unique to the test.
+    fn fixtures(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = mock::temp_path(name);
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn secret_file() {
+        let dir = fixtures("secret", &[("token", "secret\n")]);
+        let token = dir.join("token").display().to_string();
+        assert_eq!(
+            secret("token", None, Some(&token)).unwrap().as_deref(),
+            Some("secret")
+        );
+        let e = secret("token", Some("value"), Some(&token)).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "`token` and `token_file` are mutually exclusive"
+        );
+        let missing = dir.join("missing").display().to_string();
+        let e = secret("token", None, Some(&missing)).err().unwrap();
+        assert!(
+            e.to_string()
+                .starts_with(&format!("token_file `{missing}`: ")),
+            "{e}"
+        );
+        // A missing required credential is an error too.
+        let mut token = String::new();
+        assert_eq!(
+            required("token", &mut token, &None)
+                .err()
+                .unwrap()
+                .to_string(),
+            "missing field `token`"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn load_dollar_unchanged() {
+        let dir = fixtures(
+            "dollar",
+            &[(
+                "nat2.json",
+                r#"{"map": {}, "dyndns2": {"ddns": {"url": "https://example.com/nic/update",
+                    "username": "$user", "password": "pa$$w{rd}$"}},
+                    "http": {"api": {"method": "GET", "url": "http://example.com/?ip={ip}&key=$KEY"}}}"#,
+            )],
+        );
+        let cfg = load(dir.join("nat2.json")).unwrap();
+        assert_eq!(cfg.dyndns2["ddns"].username, "$user");
+        assert_eq!(cfg.dyndns2["ddns"].password, "pa$$w{rd}$");
+        assert_eq!(cfg.http["api"].url, "http://example.com/?ip={ip}&key=$KEY");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn load_include() {
+        let dir = fixtures(
+            "include",
+            &[
+                (
+                    "nat2.json",
+                    r#"{"include": ["shared/dns.yaml"], "map": {}, "cf": {"main": {"token": "a"}}}"#,
+                ),
+                // The nested include is next to the including file.
+                (
+                    "shared/dns.yaml",
+                    "include: [hooks.toml]\ncf:\n  backup:\n    token: b\n",
+                ),
+                (
+                    "shared/hooks.toml",
+                    "[webhook.hook]\nurl = \"http://127.0.0.1:9/hook\"\n",
+                ),
+            ],
+        );
+        let cfg = load(dir.join("nat2.json")).unwrap();
+        assert_eq!(cfg.cf["main"].token, "a");
+        assert_eq!(cfg.cf["backup"].token, "b");
+        assert_eq!(cfg.webhook["hook"].url, "http://127.0.0.1:9/hook");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn load_include_duplicate() {
+        let dir = fixtures(
+            "include-duplicate",
+            &[
+                (
+                    "nat2.json",
+                    r#"{"include": ["dns.json"], "map": {}, "cf": {"main": {"token": "a"}}}"#,
+                ),
+                ("dns.json", r#"{"cf": {"main": {"token": "b"}}}"#),
+            ],
+        );
+        let e = load(dir.join("nat2.json")).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "{}: duplicate watcher name `main` in cf, already defined in cf of {}",
+                dir.join("dns.json").display(),
+                dir.join("nat2.json").display()
+            )
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn parse_formats() {
+        let dir = fixtures(
+            "parse",
+            &[
+                (
+                    "nat2.json",
+                    r#"{"map": {"udp://0.0.0.0:6666": [{"name": "hook", "value": "{ip}"}]},
+                        "udp": {"stun": ["stun.example.com:3478"], "interval": 20},
+                        "webhook": {"hook": {"url": "http://127.0.0.1:9/hook"}}}"#,
+                ),
+                (
+                    "nat2.yaml",
+                    "# Comments are allowed.
+map:
+  udp://0.0.0.0:6666:
+    - name: hook
+      value: '{ip}'
+udp:
+  stun: [stun.example.com:3478]
+  interval: 20
+webhook:
+  hook:
+    url: http://127.0.0.1:9/hook
+",
+                ),
+                (
+                    "nat2.toml",
+                    r#"# Comments are allowed.
+[[map."udp://0.0.0.0:6666"]]
+name = "hook"
+value = "{ip}"
+
+[udp]
+stun = ["stun.example.com:3478"]
+interval = 20
+
+[webhook.hook]
+url = "http://127.0.0.1:9/hook"
+"#,
+                ),
+                ("bad.yaml", "map: {}\nudp:\n  interval: soon\n"),
+                ("bad.toml", "map = {}\n[udp]\ninterval = \"soon\"\n"),
+            ],
+        );
+        let json: Config = parse(&dir.join("nat2.json")).unwrap();
+        assert!(json == parse(&dir.join("nat2.yaml")).unwrap());
+        assert!(json == parse(&dir.join("nat2.toml")).unwrap());
+        let e = parse::<Config>(&dir.join("bad.yaml")).err().unwrap();
+        assert!(e.to_string().contains("at line 3 column 13"), "{e}");
+        let e = parse::<Config>(&dir.join("bad.toml")).err().unwrap();
+        assert!(e.to_string().contains("at line 3, column 12"), "{e}");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn define_names() {
+        let watchers: Watchers = serde_json::from_value(serde_json::json!({
+            "dnspod": {"main": {"secret_id": "a", "secret_key": "a"}},
+            "cf": {"main": {"token": "b"}, "cf:backup": {"token": "c"}}
+        }))
+        .unwrap();
+        let mut defined = HashMap::new();
+        let mut errors = Vec::new();
+        define(&mut defined, &watchers, Path::new("nat2.json"), &mut errors);
+        errors.sort();
+        assert_eq!(
+            errors,
+            [
+                "nat2.json: duplicate watcher name `main` in cf, already defined in dnspod of nat2.json",
+                "nat2.json: invalid watcher name `cf:backup` in cf, `:` is reserved for the kind qualifier",
+            ]
+        );
+        assert_eq!(defined.len(), 1);
+    }
+
+    #[test]
+    fn schema() {
+        let schema = serde_json::to_value(schemars::schema_for!(Config)).unwrap();
+        macro_rules! sections {
+            ($($(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])? $field:ident: $ty:ident,)*) => {
+                [$($(#[cfg($cfg)])? stringify!($field),)*]
+            };
+        }
+        for section in kinds!(sections) {
+            assert!(schema["properties"][section].is_object(), "{section}");
+        }
+        let forms: Vec<&str> = schema["properties"]["map"]["anyOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(forms, ["object", "array"]);
+
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        let hook = serde_json::json!({"hook": {"url": "http://127.0.0.1:9/hook"}});
+        for sample in [
+            serde_json::json!({
+                "map": {
+                    "tcp://0.0.0.0:6666": [{"name": "ddns", "domain": "www.example.com", "type": "A", "value": "{ip}"}],
+                    "udp://0.0.0.0:7777": {"watchers": [{"name": "hook", "value": ""}], "udp": {"interval": 20}}
+                },
+                "cf": {"ddns": {"token": "${CF_TOKEN}"}},
+                "webhook": hook
+            }),
+            serde_json::json!({
+                "map": [{"name": "a", "protocol": "udp", "listen": "0.0.0.0:7777", "watchers": [{"name": "hook", "value": ""}]}],
+                "webhook": hook
+            }),
+        ] {
+            let errors: Vec<String> = validator
+                .iter_errors(&sample)
+                .map(|e| e.to_string())
+                .collect();
+            assert!(errors.is_empty(), "{errors:?}");
+        }
+        let invalid = serde_json::json!({"map": {"http://0.0.0.0:6666": []}});
+        assert!(!validator.is_valid(&invalid));
+    }
+
+    #[test]
+    fn mapping_options_merged() {
+        let cfg: Config = serde_json::from_value(serde_json::json!({
+            "tcp": {"stun": ["global:3478"], "interval": 30},
+            "udp": {"stun": ["global:3478"], "interval": 20},
+            "map": {
+                "tcp://0.0.0.0:6666": [],
+                "tcp://0.0.0.0:7777": {
+                    "watchers": [],
+                    "tcp": {"interval": 10, "keepalive": "http://example.com"}
+                },
+                "udp://0.0.0.0:8888": {"watchers": [], "udp": {"stun": ["local:3478"]}}
+            }
+        }))
+        .unwrap();
+        // Global options are used as is.
+        let tcp = cfg.tcp_options("tcp://0.0.0.0:6666").unwrap();
+        assert!(tcp == cfg.tcp.clone().unwrap());
+        // Mapping options override the global ones field by field.
+        let tcp = cfg.tcp_options("tcp://0.0.0.0:7777").unwrap();
+        assert_eq!(tcp.stun, Some(vec!["global:3478".to_string()]));
+        assert_eq!(tcp.interval, Some(10));
+        assert_eq!(tcp.keepalive.as_deref(), Some("http://example.com"));
+        let udp = cfg.udp_options("udp://0.0.0.0:8888").unwrap();
+        assert_eq!(udp.stun, Some(vec!["local:3478".to_string()]));
+        assert_eq!(udp.interval, Some(20));
+
+        // Mapping options are used without global ones.
+        let cfg: Config = serde_json::from_value(serde_json::json!({
+            "map": [{"name": "a", "protocol": "udp", "listen": "0.0.0.0:8888", "udp": {"interval": 5}}]
+        }))
+        .unwrap();
+        assert!(cfg.tcp_options("a").is_none());
+        assert_eq!(cfg.udp_options("a").unwrap().interval, Some(5));
+        assert!(cfg.udp_options("b").is_none());
+    }
+
+    #[test]
+    fn mapping_forms() {
+        let cfg: Config = serde_json::from_value(serde_json::json!({
+            "map": {"udp://0.0.0.0:8888": [{"name": "x", "value": ""}]}
+        }))
+        .unwrap();
+        let mapping = &cfg.map["udp://0.0.0.0:8888"];
+        assert_eq!(mapping.url.as_deref(), Some("udp://0.0.0.0:8888"));
+        assert_eq!(mapping.watchers.len(), 1);
+
+        let cfg: Config = serde_json::from_value(serde_json::json!({
+            "map": [{"name": "a", "protocol": "tcp", "listen": "0.0.0.0:6666"}]
+        }))
+        .unwrap();
+        let mapping = &cfg.map["a"];
+        assert_eq!(mapping.url, None);
+        assert_eq!(mapping.protocol.as_deref(), Some("tcp"));
+        assert_eq!(mapping.listen.as_deref(), Some("0.0.0.0:6666"));
+
+        let duplicate = serde_json::from_value::<Config>(serde_json::json!({
+            "map": [{"name": "a", "protocol": "tcp"}, {"name": "a", "protocol": "udp"}]
+        }));
+        assert!(duplicate.is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::config::NamedMapping;
+use crate::mapper::Registry;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{debug, error};
+
+/// A request line, e.g. `{"id":1,"method":"remove","params":{"name":"web"}}`.
+#[derive(Deserialize, Serialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// A response line with the same id as the request, e.g. `{"id":1,"result":null}`
+/// or `{"id":1,"error":"no mapping named `web`"}`.
+#[derive(Deserialize, Serialize)]
+struct Response {
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Parameters of the requests to a single mapping.
+#[derive(Deserialize)]
+struct Name {
+    name: String,
+}
+
+/// Handle a request with the registry.
+async fn handle(registry: &Mutex<Registry>, req: Request) -> Result<Value> {
+    match req.method.as_str() {
+        "list" => Ok(json!(registry.lock().await.list())),
+        "add" => {
+            let mapping: NamedMapping = serde_json::from_value(req.params)?;
+            let name = mapping.name.clone();
+            registry.lock().await.add(name, mapping.into()).await?;
+            Ok(Value::Null)
+        }
+        "remove" => {
+            let params: Name = serde_json::from_value(req.params)?;
+            // Wait for the mapping to be closed without blocking the other requests.
+            let closed = registry.lock().await.remove(&params.name)?;
+            closed.await;
+            Ok(Value::Null)
+        }
+        "refresh" => {
+            let params: Name = serde_json::from_value(req.params)?;
+            registry.lock().await.refresh(&params.name)?;
+            Ok(Value::Null)
+        }
+        "notify" => {
+            let params: Name = serde_json::from_value(req.params)?;
+            registry.lock().await.notify(&params.name)?;
+            Ok(Value::Null)
+        }
+        method => Err(anyhow!("unknown method `{method}`")),
+    }
+}
+
+/// Control socket which lists and changes the running mappings.
+///
+/// Each connection talks JSON lines: a request is a single line, and it is answered with
+/// a line carrying the same id.
+///
+/// The socket file is removed when the server is dropped.
+pub struct Server {
+    /// Path of the Unix domain socket.
+    path: String,
+    /// Socket bound but not yet served.
+    listener: Option<UnixListener>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// Remove the socket file left by an instance which did not exit cleanly.
+/// A file which is not a socket, or a socket of a running instance, is left untouched.
+fn remove_stale(path: &str) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(anyhow!("{path}: {e}")),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(anyhow!("{path}: file exists and is not a socket"));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(anyhow!("{path}: socket is in use by another instance")),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            fs::remove_file(path).map_err(|e| anyhow!("{path}: {e}"))
+        }
+        Err(e) => Err(anyhow!("{path}: {e}")),
+    }
+}
+
+impl Server {
+    /// Bind the Unix domain socket, replacing the stale one.
+    /// Only the owner is allowed to connect.
+    pub fn bind(path: String) -> Result<Self> {
+        remove_stale(&path)?;
+        // The socket is created in a private directory and moved into place once its mode
+        // is restricted, so others never get a chance to connect.
+        let parent = Path::new(&path)
+            .parent()
+            .filter(|v| !v.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let dir = parent.join(format!(".nat2.{}.sock.d", std::process::id()));
+        let tmp = dir.join("sock");
+        let _ = fs::remove_dir_all(&dir);
+        let listener = DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .and_then(|_| UnixListener::bind(&tmp))
+            .and_then(|listener| {
+                fs::set_permissions(&tmp, Permissions::from_mode(0o600))?;
+                fs::rename(&tmp, &path)?;
+                Ok(listener)
+            });
+        let _ = fs::remove_dir_all(&dir);
+        let listener = listener.map_err(|e| anyhow!("{path}: {e}"))?;
+        Ok(Self {
+            path,
+            listener: Some(listener),
+            handle: None,
+        })
+    }
+
+    /// Answer the requests with the registry.
+    pub fn serve(&mut self, registry: Arc<Mutex<Registry>>) {
+        let Some(listener) = self.listener.take() else {
+            return;
+        };
+        self.handle = Some(tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!(op = "control", "{e}");
+                        continue;
+                    }
+                };
+                let registry = registry.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(stream, &registry).await {
+                        debug!(op = "control", "{e}");
+                    }
+                });
+            }
+        }));
+    }
+}
+
+impl Drop for Server {
+    /// Stop listening and remove the socket file.
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+        }
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Answer the requests of a connection until it is closed.
+async fn serve(stream: UnixStream, registry: &Mutex<Registry>) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let resp = match serde_json::from_str::<Request>(&line) {
+            Ok(req) => {
+                let id = req.id.clone();
+                match handle(registry, req).await {
+                    Ok(result) => Response {
+                        id,
+                        result: Some(result),
+                        error: None,
+                    },
+                    Err(e) => Response {
+                        id,
+                        result: None,
+                        error: Some(e.to_string()),
+                    },
+                }
+            }
+            Err(e) => Response {
+                id: Value::Null,
+                result: None,
+                error: Some(e.to_string()),
+            },
+        };
+        let mut line = serde_json::to_vec(&resp)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+    }
+    Ok(())
+}
+
+/// Send a request to the control socket of a running instance and return the result.
+pub async fn request(path: &str, method: String, params: Value) -> Result<Value> {
+    let mut stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("{path}: {e}"))?;
+    let mut line = serde_json::to_vec(&Request {
+        id: json!(1),
+        method,
+        params,
+    })?;
+    line.push(b'\n');
+    stream.write_all(&line).await?;
+    let line = BufReader::new(stream)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or(anyhow!("connection closed unexpectedly"))?;
+    let resp: Response = serde_json::from_str(&line)?;
+    match resp.error {
+        Some(e) => Err(anyhow!(e)),
+        None => Ok(resp.result.unwrap_or_default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::mapper::run;
+    use crate::watcher::mock;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = mock::temp_path("control");
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("nat2.sock").to_str().unwrap().to_string();
+        let cfg: Config = serde_json::from_value(json!({
+            "map": [{"name": "web", "protocol": "udp", "listen": "127.0.0.1:0"}],
+            "upnp": false,
+            "udp": {"stun": ["127.0.0.1:9"], "interval": 3600},
+        }))
+        .unwrap();
+        let registry = Arc::new(Mutex::new(run(cfg).await.unwrap()));
+        let mut server = Server::bind(path.clone()).unwrap();
+        server.serve(registry);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Only the socket is left in the directory.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let list = request(&path, "list".into(), Value::Null).await.unwrap();
+        assert_eq!(list[0]["name"], "web");
+        let e = request(&path, "refresh".into(), json!({"name": "db"}))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("db"), "{e}");
+        let e = request(&path, "stop".into(), Value::Null)
+            .await
+            .unwrap_err();
+        assert_eq!(e.to_string(), "unknown method `stop`");
+
+        drop(server);
+        assert!(!Path::new(&path).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,14 +1,18 @@
 mod client;
 mod config;
+#[cfg(unix)]
+mod control;
 mod mapper;
 mod upnp;
 mod watcher;
 
 use crate::mapper::run;
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
 use std::env;
-use tracing::info;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
@@ -19,26 +23,136 @@ struct Opt {
     config: String,
     #[arg(long)]
     debug: bool,
+    /// Path of the control socket to list and change the running mappings (Unix only).
+    #[arg(long)]
+    control: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate the config file without touching the network.
+    Check {
+        #[arg(short = 'c', long, default_value = "config.json")]
+        config: String,
+    },
+    /// Print the JSON Schema of the config file.
+    Schema,
+    /// Send a request to the control socket of a running instance.
+    Ctl {
+        #[arg(short = 's', long)]
+        control: String,
+        /// One of `list`, `add`, `remove`, `refresh` and `notify`.
+        method: String,
+        /// Parameters in JSON, e.g. `{"name":"web"}`.
+        params: Option<String>,
+    },
+}
+
+/// Signals handled by nat2.
+enum Signal {
+    /// Close connections and exit.
+    Terminate,
+    /// Reload the configuration file.
+    Reload,
+}
+
+#[cfg(unix)]
+struct Signals {
+    sigint: tokio::signal::unix::Signal,
+    sigterm: tokio::signal::unix::Signal,
+    sighup: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            sigint: signal(SignalKind::interrupt())?,
+            sigterm: signal(SignalKind::terminate())?,
+            sighup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) -> Result<Signal> {
+        tokio::select! {
+            _ = self.sigint.recv() => Ok(Signal::Terminate),
+            _ = self.sigterm.recv() => Ok(Signal::Terminate),
+            _ = self.sighup.recv() => Ok(Signal::Reload),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> Result<Self> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) -> Result<Signal> {
+        tokio::signal::ctrl_c().await?;
+        Ok(Signal::Terminate)
+    }
+}
+
+/// Validate the config file and print all of the errors.
+async fn check(path: &str) -> Result<()> {
+    let cfg = config::load(path)?;
+    let (errors, skipped) = mapper::check(&cfg).await;
+    for name in skipped.iter() {
+        println!("{path}: skipped the metadata of {name}, it is validated at startup");
+    }
+    if errors.is_empty() {
+        println!("{path}: ok");
+        return Ok(());
+    }
+    for e in errors.iter() {
+        eprintln!("{path}: {e}");
+    }
+    Err(anyhow!("found {} error(s) in {path}", errors.len()))
 }
 
+/// Send a request to the control socket and print the result.
 #[cfg(unix)]
-async fn trap_signals() -> Result<()> {
-    use tokio::signal::unix::{signal, SignalKind};
-    let mut sigint = signal(SignalKind::interrupt())?;
-    let mut sigterm = signal(SignalKind::terminate())?;
-    tokio::select! {
-        _ = sigint.recv() => {},
-        _ = sigterm.recv() => {},
+async fn ctl(path: &str, method: String, params: Option<String>) -> Result<()> {
+    let params = match params {
+        Some(v) => serde_json::from_str(&v)?,
+        None => serde_json::Value::Null,
+    };
+    let result = control::request(path, method, params).await?;
+    if !result.is_null() {
+        println!("{}", serde_json::to_string_pretty(&result)?);
     }
     Ok(())
 }
 
 #[cfg(not(unix))]
-use tokio::signal::ctrl_c as trap_signals;
+async fn ctl(_: &str, _: String, _: Option<String>) -> Result<()> {
+    Err(anyhow!("control socket is only supported on Unix"))
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opt = Opt::parse();
+    match opt.command {
+        Some(Command::Check { config }) => return check(&config).await,
+        Some(Command::Schema) => {
+            let schema = schemars::schema_for!(config::Config);
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        Some(Command::Ctl {
+            control,
+            method,
+            params,
+        }) => return ctl(&control, method, params).await,
+        None => {}
+    }
     if env::var("RUST_LOG").is_err() {
         if opt.debug {
             env::set_var("RUST_LOG", "nat2=debug,reqwest=debug");
@@ -46,14 +160,38 @@ async fn main() -> Result<()> {
             env::set_var("RUST_LOG", "nat2=info,reqwest=warn");
         }
     }
-    let cfg = config::load(opt.config)?;
+    let cfg = config::load(&opt.config)?;
     tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .with_timer(tracing_subscriber::fmt::time::time())
         .init();
-    let mapper = run(cfg).await?;
-    trap_signals().await?;
+    let mut signals = Signals::new()?;
+    // The socket is bound before the watchers are started, so a running instance is
+    // reported before anything is touched.
+    #[cfg(unix)]
+    let mut server = opt.control.map(control::Server::bind).transpose()?;
+    #[cfg(not(unix))]
+    if opt.control.is_some() {
+        return Err(anyhow!("control socket is only supported on Unix"));
+    }
+    let registry = Arc::new(Mutex::new(run(cfg).await?));
+    #[cfg(unix)]
+    if let Some(server) = server.as_mut() {
+        server.serve(registry.clone());
+    }
+    while let Signal::Reload = signals.recv().await? {
+        info!("reloading configuration");
+        let result = match config::load(&opt.config) {
+            Ok(cfg) => registry.lock().await.reload(cfg).await,
+            Err(e) => Err(anyhow!("{e}, keep running with the old configuration")),
+        };
+        if let Err(e) = result {
+            error!("{e}");
+        }
+    }
     info!("closing connections");
-    mapper.close().await;
+    #[cfg(unix)]
+    drop(server);
+    registry.lock().await.close().await;
     Ok(())
 }
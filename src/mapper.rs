@@ -1,25 +1,40 @@
 use crate::client;
 use crate::client::{Callback, Client};
-use crate::config::{Config, Tcp, Udp};
+use crate::config::{self, kinds, Config, Mapping, Metadata, Tcp, Udp};
 use crate::upnp::{PortMap, Upnp};
 use crate::watcher::alidns::AliDns;
 use crate::watcher::cf::Cloudflare;
 use crate::watcher::dnspod::DnsPod;
+use crate::watcher::dyndns2::DynDns2;
+use crate::watcher::file::File;
 use crate::watcher::http::Http;
+use crate::watcher::matrix::Matrix;
+use crate::watcher::mqtt::Mqtt;
+use crate::watcher::plugin::Plugin;
+use crate::watcher::qbittorrent::QBittorrent;
 use crate::watcher::script::Script;
-use crate::watcher::Watcher;
+use crate::watcher::smtp::Smtp;
+use crate::watcher::telegram::Telegram;
+use crate::watcher::transmission::Transmission;
+#[cfg(feature = "wasm")]
+use crate::watcher::wasm::Wasm;
+use crate::watcher::webhook::Webhook;
+use crate::watcher::wireguard::WireGuard;
+use crate::watcher::{Event, PermanentError, Watcher};
 use anyhow::{anyhow, Result};
 use futures::future::join_all;
 use igd_next::PortMappingProtocol::{TCP, UDP};
+use serde::Serialize;
 use std::collections::HashMap;
+use std::future::Future;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ops::Deref;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use stun::xoraddr::XorMappedAddress;
 use stun::Error::ErrSchemeType;
-use tokio::sync::broadcast;
-use tokio::sync::broadcast::Sender;
 use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::task::JoinHandle;
 use tracing::{error, info};
 use url::ParseError::{EmptyHost, InvalidPort};
@@ -32,6 +47,8 @@ struct Mapper {
     handle: Client,
     /// Public IPv4 address and port.
     public: Option<String>,
+    /// Previous public IPv4 address and port.
+    previous: Option<SocketAddr>,
 }
 
 impl Mapper {
@@ -60,6 +77,7 @@ impl Mapper {
             protocol: "tcp",
             handle: c.build().await?,
             public: None,
+            previous: None,
         })
     }
 
@@ -82,6 +100,7 @@ impl Mapper {
             protocol: "udp",
             handle: c.build().await?,
             public: None,
+            previous: None,
         })
     }
 
@@ -93,6 +112,9 @@ impl Mapper {
                 changed = false;
             }
         }
+        if changed {
+            self.previous = self.public.as_ref().and_then(|v| v.parse().ok());
+        }
         self.public = Some(addr.to_string());
         changed
     }
@@ -107,173 +129,1192 @@ impl Mapper {
         self.handle.local_addr()
     }
 
+    /// Send a binding request immediately.
+    fn refresh(&self) {
+        self.handle.refresh()
+    }
+
     /// Stop the internal NAT client.
     fn close(&self) {
         self.handle.close()
     }
 }
 
-fn upnp_enabled(url: &String) -> bool {
-    if let Ok(url) = Url::parse(url.as_str()) {
-        match url.scheme() {
-            "tcp+upnp" | "upnp+tcp" | "udp+upnp" | "upnp+udp" => true,
-            _ => false,
+/// Watchers by name.
+type WatcherMap = HashMap<String, Arc<dyn Watcher + Send + Sync>>;
+
+/// Watcher list of a mapping, together with the metadata.
+type Watchers = Vec<(Arc<dyn Watcher + Send + Sync>, Metadata)>;
+
+/// Returns true if both lists have the same watcher instances and metadata.
+fn same_watchers(a: &Watchers, b: &Watchers) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .all(|((w1, md1), (w2, md2))| Arc::ptr_eq(w1, w2) && md1 == md2)
+}
+
+/// Requests to a running mapping.
+enum Command {
+    /// Send a binding request immediately.
+    Refresh,
+    /// Notify all of the watchers of the current mapped address.
+    Notify,
+}
+
+/// Status of a running mapping.
+#[derive(Clone, Serialize)]
+pub struct Status {
+    /// Mapping name.
+    pub name: String,
+    /// `tcp` or `udp`.
+    pub protocol: &'static str,
+    /// Socket binding address of the NAT client.
+    pub local_addr: SocketAddr,
+    /// Public address and port, if it is known.
+    pub public: Option<String>,
+    /// UPnP port mapping.
+    pub upnp: Option<UpnpStatus>,
+    /// Watcher list.
+    pub watchers: Vec<WatcherStatus>,
+}
+
+/// Port mapping of the gateway.
+#[derive(Clone, Serialize)]
+pub struct UpnpStatus {
+    /// Gateway forwards traffics from external port to the forward address.
+    pub forward: SocketAddr,
+    /// The mapped external port on the gateway.
+    pub external_port: u16,
+}
+
+/// Status of a watcher in a mapping.
+#[derive(Clone, Serialize)]
+pub struct WatcherStatus {
+    /// Name in the metadata.
+    pub name: String,
+    /// Watcher kind.
+    pub kind: &'static str,
+    /// Whether the watcher is waiting to be notified of the current mapped address.
+    pub pending: bool,
+    /// Error of the last notification.
+    pub error: Option<String>,
+}
+
+impl WatcherStatus {
+    fn new(watcher: &Arc<dyn Watcher + Send + Sync>, md: &Metadata) -> Self {
+        Self {
+            name: md.name.clone(),
+            kind: watcher.kind(),
+            pending: true,
+            error: None,
         }
-    } else {
-        false
     }
 }
 
-pub struct Closer {
-    tasks: Vec<JoinHandle<()>>,
-    close: Sender<()>,
+/// A running mapping.
+struct Task {
+    /// Stop the mapping.
+    close: oneshot::Sender<()>,
+    /// Replace the watcher list of the mapping.
+    watchers: watch::Sender<Arc<Watchers>>,
+    /// Send requests to the mapping.
+    commands: mpsc::Sender<Command>,
+    /// The latest status of the mapping.
+    status: Arc<Mutex<Status>>,
+    handle: JoinHandle<()>,
 }
 
-impl Closer {
-    pub async fn close(self) {
-        self.close.send(()).unwrap();
-        join_all(self.tasks).await;
+impl Task {
+    async fn close(self) {
+        let _ = self.close.send(());
+        let _ = self.handle.await;
     }
 }
 
-/// Merge different watchers into a single hashmap.
-macro_rules! map_watcher {
-    ($($bind:pat = $cfg:expr => $watcher:expr),*) => {{
-        let mut watchers: HashMap<String, Arc<dyn Watcher + Send + Sync>> = HashMap::new();
-        $(
-            for (key, value) in $cfg.into_iter() {
-                let name = key.clone();
-                let $bind = (key, value);
-                watchers.insert(name, Arc::new($watcher));
-            }
-        )*
-        watchers
-    }};
+/// Running mappings and watchers.
+pub struct Registry {
+    /// The configuration in use, including the mappings added at runtime.
+    cfg: Config,
+    /// Watcher list.
+    watchers: WatcherMap,
+    /// UPnP client, created when the first UPnP mapping is added.
+    upnp: Option<Arc<Upnp>>,
+    /// Mapping list.
+    tasks: HashMap<String, Task>,
+    /// Mappings added at runtime, which are kept across reloads.
+    added: HashMap<String, Mapping>,
 }
 
-pub async fn run(cfg: Config) -> Result<Closer> {
-    // Watcher list.
-    let watcher_map = map_watcher!(
-        (key, value) = cfg.dnspod => DnsPod::new(key, value.secret_id, value.secret_key),
-        (key, value) = cfg.http => Http::new(key, value.url, value.method.as_str(), value.body, value.headers)?,
-        (key, value) = cfg.script => Script::new(key, value.path, value.args),
-        (key, value) = cfg.alidns => AliDns::new(key, value.secret_id, value.secret_key, value.url)?,
-        (key, value) = cfg.cf => Cloudflare::new(key, value.token)
-    );
-    // UPnP feature.
-    let global_upnp = !matches!(cfg.upnp, Some(false));
-    let upnp = if global_upnp || cfg.map.keys().any(upnp_enabled) {
-        Some(Arc::new(Upnp::new().await?))
-    } else {
-        None
-    };
-    let (close, _) = broadcast::channel(1);
-    let mut tasks = Vec::with_capacity(cfg.map.len());
-    // Mapper list.
-    for (key, value) in cfg.map.into_iter() {
-        let url = Url::parse(key.as_str())?;
-        let ip = url.host().ok_or(anyhow!("{EmptyHost} in {key}"))?;
-        let port = url.port().ok_or(anyhow!("{InvalidPort} in {key}"))?;
-        let mut local_addr = format!("{ip}:{port}");
+impl Registry {
+    pub async fn close(&mut self) {
+        join_all(self.tasks.drain().map(|(_, task)| task.close())).await;
+        stop(self.watchers.values()).await;
+    }
+
+    /// Returns the status of all mappings, sorted by name.
+    pub fn list(&self) -> Vec<Status> {
+        let mut list: Vec<Status> = self
+            .tasks
+            .values()
+            .map(|v| v.status.lock().unwrap().clone())
+            .collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Start a new mapping with the running watchers.
+    pub async fn add(&mut self, name: String, mapping: Mapping) -> Result<()> {
+        if self.tasks.contains_key(&name) {
+            return Err(anyhow!("mapping `{name}` already exists"));
+        }
+        let endpoint = endpoint(&name, &mapping, self.cfg.upnp)?;
+        let watchers = resolve_watchers(&name, &mapping.watchers, &self.watchers).await?;
+        // The mapping options are looked up by name.
+        self.cfg.map.insert(name.clone(), mapping.clone());
+        match spawn(name.clone(), endpoint, watchers, &self.cfg, &mut self.upnp).await {
+            Ok(task) => {
+                info!(mapper = name, "mapping added");
+                self.tasks.insert(name.clone(), task);
+                self.added.insert(name, mapping);
+                Ok(())
+            }
+            Err(e) => {
+                self.cfg.map.remove(&name);
+                Err(e)
+            }
+        }
+    }
+
+    /// Stop a mapping. It is started again by the next reload if it is in the configuration file.
+    /// Returns a future which completes when the mapping is closed, so it can be awaited
+    /// without holding the registry.
+    pub fn remove(&mut self, name: &str) -> Result<impl Future<Output = ()>> {
+        let task = self
+            .tasks
+            .remove(name)
+            .ok_or(anyhow!("no mapping named `{name}`"))?;
+        info!(mapper = name, "stopping mapping");
+        self.cfg.map.remove(name);
+        self.added.remove(name);
+        Ok(task.close())
+    }
+
+    /// Send a binding request of the mapping immediately.
+    pub fn refresh(&self, name: &str) -> Result<()> {
+        self.command(name, Command::Refresh)
+    }
+
+    /// Notify all of the watchers of the mapping again.
+    pub fn notify(&self, name: &str) -> Result<()> {
+        self.command(name, Command::Notify)
+    }
+
+    /// Queue a command of the mapping without waiting for it to be handled.
+    fn command(&self, name: &str, command: Command) -> Result<()> {
+        let task = self
+            .tasks
+            .get(name)
+            .ok_or(anyhow!("no mapping named `{name}`"))?;
+        task.commands.try_send(command).map_err(|e| match e {
+            TrySendError::Full(_) => anyhow!("mapping `{name}` is busy, try again later"),
+            TrySendError::Closed(_) => anyhow!("mapping `{name}` is closed"),
+        })
+    }
+
+    /// Create the watchers of the new configuration, reusing the unchanged ones,
+    /// and resolve the endpoint and watcher list of each mapping.
+    async fn resolve(
+        &self,
+        cfg: &Config,
+    ) -> Result<(WatcherMap, HashMap<String, (Endpoint, Watchers)>)> {
+        let (watcher_map, errors) = map_watchers(cfg, Some((&self.cfg, &self.watchers)));
+        if let Some((_, e)) = errors.into_iter().next() {
+            return Err(e);
+        }
+        let mut lists = HashMap::with_capacity(cfg.map.len());
+        for (key, value) in cfg.map.iter() {
+            let endpoint = endpoint(key, value, cfg.upnp)?;
+            let watchers = resolve_watchers(key, &value.watchers, &watcher_map).await?;
+            lists.insert(key.clone(), (endpoint, watchers));
+        }
+        Ok((watcher_map, lists))
+    }
+
+    /// Validate the watcher metadata and start all of the mappings in the configuration.
+    async fn spawn_all(&mut self) -> Result<()> {
+        let cfg = &self.cfg;
         // Validate watcher metadata.
-        let mut watchers = Vec::with_capacity(value.len());
-        for (i, md) in value.into_iter().enumerate() {
-            if let Some(watcher) = watcher_map.get(&md.name) {
-                watcher
-                    .validate(&md)
-                    .map_err(|e| anyhow!("{e} in {key} at index {i}"))?;
-                watchers.push((watcher.clone(), md));
-            } else {
-                return Err(anyhow!(
-                    "no watcher named `{}` in {key} at index {i}",
-                    md.name
-                ));
+        let mut lists = Vec::with_capacity(cfg.map.len());
+        for (key, value) in cfg.map.iter() {
+            lists.push((
+                key.clone(),
+                endpoint(key, value, cfg.upnp)?,
+                resolve_watchers(key, &value.watchers, &self.watchers).await?,
+            ));
+        }
+        // UPnP feature.
+        if lists.iter().any(|(_, v, _)| v.forward.is_some()) {
+            self.upnp = Some(Arc::new(Upnp::new().await?));
+        }
+        // Mapper list.
+        for (key, endpoint, watchers) in lists.into_iter() {
+            let task = spawn(key.clone(), endpoint, watchers, cfg, &mut self.upnp).await?;
+            self.tasks.insert(key, task);
+        }
+        Ok(())
+    }
+
+    /// Apply the new configuration. Only the changed mappings and watchers are restarted.
+    /// The running ones are left untouched if the new configuration is invalid.
+    /// Errors after the new configuration is applied, such as a mapping failing to bind
+    /// its port, are returned together once all of the others are started.
+    pub async fn reload(&mut self, mut cfg: Config) -> Result<()> {
+        // The mappings added at runtime are replaced by the ones with the same name in the file.
+        for (name, mapping) in self.added.iter() {
+            cfg.map
+                .entry(name.clone())
+                .or_insert_with(|| mapping.clone());
+        }
+        // Nothing is stopped or started until the whole configuration is resolved.
+        let (watcher_map, lists) = self
+            .resolve(&cfg)
+            .await
+            .map_err(|e| anyhow!("{e}, keep running with the old configuration"))?;
+        let started = difference(&watcher_map, &self.watchers);
+        if let Err(e) = start(started.iter()).await {
+            stop(started.iter()).await;
+            return Err(anyhow!("{e}, keep running with the old configuration"));
+        }
+        // Plugins and components skip the validation until they are started.
+        for (key, (_, watchers)) in lists.iter() {
+            for (watcher, md) in watchers.iter() {
+                if !watcher.validates_when_started()
+                    || !started.iter().any(|v| Arc::ptr_eq(v, watcher))
+                {
+                    continue;
+                }
+                if let Err(e) = watcher.validate(md).await {
+                    stop(started.iter()).await;
+                    return Err(anyhow!(
+                        "{e} in {key}, keep running with the old configuration"
+                    ));
+                }
             }
         }
-        let mut pm: Option<(Arc<Upnp>, PortMap)> = None;
-        let (tx, mut rx) = channel(1);
-        let mut mapper = match url.scheme() {
-            "tcp" | "tcp+upnp" | "upnp+tcp" => {
-                if url.scheme() != "tcp" || global_upnp {
-                    let upnp = upnp.as_ref().unwrap();
-                    let map = upnp.add_port(TCP, local_addr.parse()?).await?;
-                    local_addr = map.local_addr();
-                    pm = Some((upnp.clone(), map));
+        let mut errors = Vec::new();
+        // Mappings are restarted if their endpoints or options are changed.
+        let keys: Vec<String> = self.tasks.keys().cloned().collect();
+        for key in keys {
+            let old = endpoint(&key, &self.cfg.map[&key], self.cfg.upnp).ok();
+            let restart = match lists.get(&key) {
+                None => true,
+                Some((new, _)) if old.as_ref() != Some(new) => true,
+                Some((new, _)) if new.protocol == "tcp" => {
+                    cfg.tcp_options(&key) != self.cfg.tcp_options(&key)
                 }
-                Mapper::new_tcp(key, local_addr, &cfg.tcp, tx).await?
+                Some(_) => cfg.udp_options(&key) != self.cfg.udp_options(&key),
+            };
+            if restart {
+                info!(mapper = key, "stopping mapping");
+                self.tasks.remove(&key).unwrap().close().await;
+            }
+        }
+        for (key, (endpoint, watchers)) in lists.into_iter() {
+            if let Some(task) = self.tasks.get(&key) {
+                task.watchers.send_if_modified(|v| {
+                    if same_watchers(v, &watchers) {
+                        return false;
+                    }
+                    info!(mapper = key, "watcher list updated");
+                    *v = Arc::new(watchers);
+                    true
+                });
+                continue;
             }
-            "udp" | "udp+upnp" | "upnp+udp" => {
-                if url.scheme() != "udp" || global_upnp {
-                    let upnp = upnp.as_ref().unwrap();
-                    let map = upnp.add_port(UDP, local_addr.parse()?).await?;
-                    local_addr = map.local_addr();
-                    pm = Some((upnp.clone(), map));
+            match spawn(key.clone(), endpoint, watchers, &cfg, &mut self.upnp).await {
+                Ok(task) => {
+                    self.tasks.insert(key, task);
                 }
-                Mapper::new_udp(key, local_addr, &cfg.udp, tx).await?
+                Err(e) => errors.push(anyhow!("failed to start mapping {key}: {e}")),
             }
-            _ => Err(anyhow!("{ErrSchemeType} {}", url.scheme()))?,
+        }
+        self.added
+            .retain(|name, mapping| cfg.map.get(name) == Some(mapping));
+        // The mappings have moved on to the new watchers.
+        stop(difference(&self.watchers, &watcher_map).iter()).await;
+        self.cfg = cfg;
+        self.watchers = watcher_map;
+        match errors.len() {
+            0 => Ok(()),
+            _ => Err(anyhow!(
+                "configuration reloaded with {} error(s): {}",
+                errors.len(),
+                errors
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            )),
+        }
+    }
+}
+
+/// Returns the watchers of `a` which are not in `b`.
+fn difference(a: &WatcherMap, b: &WatcherMap) -> Vec<Arc<dyn Watcher + Send + Sync>> {
+    a.values()
+        .filter(|w| !b.values().any(|v| Arc::ptr_eq(v, w)))
+        .cloned()
+        .collect()
+}
+
+/// Start the watchers. Returns the first error if any of them fails to start.
+async fn start<'a>(
+    watchers: impl Iterator<Item = &'a Arc<dyn Watcher + Send + Sync>>,
+) -> Result<()> {
+    join_all(watchers.map(|v| async move {
+        v.start()
+            .await
+            .map_err(|e| anyhow!("{e} in {}.{}", v.kind(), v.name()))
+    }))
+    .await
+    .into_iter()
+    .collect()
+}
+
+/// Stop the watchers.
+async fn stop<'a>(watchers: impl Iterator<Item = &'a Arc<dyn Watcher + Send + Sync>>) {
+    join_all(watchers.map(|v| v.stop())).await;
+}
+
+/// Configuration of a watcher kind, which creates the watcher.
+trait Build {
+    fn build(self, name: String) -> Result<Arc<dyn Watcher + Send + Sync>>;
+}
+
+/// Implement `Build` for the configuration types.
+/// A kind without an implementation is a compile error in `map_watchers`.
+macro_rules! build {
+    ($($(#[$meta:meta])* $ty:ident: ($key:ident, $value:ident) => $watcher:expr),*) => {
+        $(
+            $(#[$meta])*
+            impl Build for config::$ty {
+                fn build(self, $key: String) -> Result<Arc<dyn Watcher + Send + Sync>> {
+                    let $value = self;
+                    Ok(Arc::new($watcher))
+                }
+            }
+        )*
+    };
+}
+
+build!(
+    DnsPod: (key, value) => DnsPod::new(key, value.secret_id, value.secret_key),
+    Http: (key, value) => Http::new(key, value.url, value.method.as_str(), value.body, value.headers)?,
+    Script: (key, value) => Script::new(
+        key,
+        value.path,
+        value.args,
+        value.dir,
+        value.env,
+        value.timeout,
+        value.retry_codes
+    ),
+    AliDNS: (key, value) => AliDns::new(key, value.secret_id, value.secret_key, value.url)?,
+    Cloudflare: (key, value) => Cloudflare::new(key, value.token, value.zone_id, value.cleanup),
+    DynDns2: (key, value) => DynDns2::new(key, value.url, value.username, value.password)?,
+    Telegram: (key, value) => Telegram::new(key, value.token, value.chat_id, value.url, value.message)?,
+    Matrix: (key, value) => Matrix::new(key, value.url, value.token, value.room_id, value.message)?,
+    Webhook: (key, value) => Webhook::new(key, value.url, value.format, value.message)?,
+    Smtp: (key, value) => Smtp::new(
+        key,
+        value.host,
+        value.port,
+        value.tls,
+        value.username.zip(value.password),
+        value.from,
+        value.to,
+        value.subject,
+        value.body
+    )?,
+    Mqtt: (key, value) => Mqtt::new(
+        key,
+        value.url,
+        value.client_id,
+        value.username.zip(value.password),
+        value.topic,
+        value.qos,
+        value.retain,
+        value.status_topic
+    )?,
+    File: (key, value) => File::new(key, value.template, value.path, value.command)?,
+    QBittorrent: (key, value) => QBittorrent::new(
+        key,
+        value.url,
+        value.username.zip(value.password),
+        value.announce_ip
+    )?,
+    Transmission: (key, value) => Transmission::new(key, value.url, value.username.zip(value.password))?,
+    Plugin: (key, value) => Plugin::new(
+        key,
+        value.path,
+        value.args,
+        value.dir,
+        value.env,
+        value.timeout
+    ),
+    #[cfg(feature = "wasm")]
+    Wasm: (key, value) => Wasm::new(key, value.path, value.allow, value.timeout, value.memory)?,
+    WireGuard: (key, value) => WireGuard::new(
+        key,
+        value.remote,
+        value.listen,
+        value.interface,
+        value.token
+    )?
+);
+
+/// Create the watcher list.
+/// The watcher instance is reused if its configuration is the same as before.
+/// Watchers that fail to be created are left out, and the errors are returned with their names.
+fn map_watchers(
+    cfg: &Config,
+    old: Option<(&Config, &WatcherMap)>,
+) -> (WatcherMap, Vec<(String, anyhow::Error)>) {
+    let mut watchers: WatcherMap = HashMap::new();
+    let mut errors = Vec::new();
+    macro_rules! map {
+        ($($(#[doc = $doc:literal])* $(#[cfg($cfg:meta)])? $field:ident: $ty:ident,)*) => {
+            $(
+                $(#[cfg($cfg)])?
+                for (key, value) in cfg.$field.iter() {
+                    let old = old.and_then(|(cfg, watchers)| {
+                        (cfg.$field.get(key) == Some(value)).then(|| watchers.get(key)).flatten()
+                    });
+                    if let Some(watcher) = old {
+                        watchers.insert(key.clone(), Arc::clone(watcher));
+                        continue;
+                    }
+                    match value.clone().build(key.clone()) {
+                        Ok(watcher) => {
+                            watchers.insert(key.clone(), watcher);
+                        }
+                        Err(e) => errors.push((
+                            key.clone(),
+                            anyhow!("{e} in {}.{key}", stringify!($field)),
+                        )),
+                    }
+                }
+            )*
         };
-        let mut close = close.subscribe();
-        tasks.push(tokio::spawn(async move {
-            let mut failed: isize = -1;
-            loop {
-                tokio::select! {
-                    Some(addr) = rx.recv() => {
-                        if let Some((upnp, pm)) = pm.as_mut() {
-                            if let Err(e) = upnp.renew_port(pm).await {
-                                error!(mapper = mapper.name(), upnp="renew", "{e}");
-                            }
+    }
+    kinds!(map);
+    (watchers, errors)
+}
+
+/// Find the watcher of the metadata and validate it.
+async fn resolve_watcher(
+    key: &str,
+    i: usize,
+    md: &Metadata,
+    watcher_map: &WatcherMap,
+) -> Result<Arc<dyn Watcher + Send + Sync>> {
+    let (kind, name) = md.watcher();
+    let watcher = watcher_map.get(name).ok_or(anyhow!(
+        "no watcher named `{}` in {key} at index {i}",
+        md.name
+    ))?;
+    if let Some(kind) = kind.filter(|v| *v != watcher.kind()) {
+        return Err(anyhow!(
+            "watcher `{name}` is {}, not {kind} in {key} at index {i}",
+            watcher.kind()
+        ));
+    }
+    watcher
+        .validate(md)
+        .await
+        .map_err(|e| anyhow!("{e} in {key} at index {i}"))?;
+    Ok(watcher.clone())
+}
+
+/// Find the watchers of a mapping and validate the metadata.
+async fn resolve_watchers(
+    key: &str,
+    value: &[Metadata],
+    watcher_map: &WatcherMap,
+) -> Result<Watchers> {
+    let mut watchers = Vec::with_capacity(value.len());
+    for (i, md) in value.iter().enumerate() {
+        watchers.push((resolve_watcher(key, i, md, watcher_map).await?, md.clone()));
+    }
+    Ok(watchers)
+}
+
+/// Validate the configuration without touching the network.
+/// Returns all of the errors found, and the watchers whose metadata is not validated
+/// because they are not started.
+pub async fn check(cfg: &Config) -> (Vec<anyhow::Error>, Vec<String>) {
+    let (watcher_map, errors) = map_watchers(cfg, None);
+    let mut skipped = Vec::new();
+    let failed: Vec<String> = errors.iter().map(|(name, _)| name.clone()).collect();
+    let mut errors: Vec<anyhow::Error> = errors.into_iter().map(|(_, e)| e).collect();
+    for (key, value) in cfg.map.iter() {
+        if let Err(e) = endpoint(key, value, cfg.upnp) {
+            errors.push(e);
+        }
+        for (i, md) in value.watchers.iter().enumerate() {
+            // The watcher itself is invalid, which has been reported.
+            if failed.iter().any(|v| v == md.watcher().1) {
+                continue;
+            }
+            match resolve_watcher(key, i, md, &watcher_map).await {
+                Ok(watcher) if watcher.validates_when_started() => {
+                    let name = format!("{}.{}", watcher.kind(), watcher.name());
+                    if !skipped.contains(&name) {
+                        skipped.push(name);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+    (errors, skipped)
+}
+
+/// Local endpoint of a mapping.
+#[derive(PartialEq)]
+struct Endpoint {
+    /// `tcp` or `udp`.
+    protocol: &'static str,
+    /// Socket binding address of the NAT client.
+    /// If UPnP is used, it is an IP address and the port is the mapped external port.
+    listen: String,
+    /// Address of the local service that the gateway forwards traffics to if UPnP is used.
+    forward: Option<SocketAddr>,
+}
+
+/// Resolve the local endpoint of a mapping from either the url or the named form.
+fn endpoint(key: &str, mapping: &Mapping, global_upnp: Option<bool>) -> Result<Endpoint> {
+    let global_upnp = !matches!(global_upnp, Some(false));
+    if let Some(url) = &mapping.url {
+        let (scheme, addr) = parse_url(url)?;
+        let protocol = if scheme.contains("tcp") { "tcp" } else { "udp" };
+        if scheme.contains("upnp") || global_upnp {
+            let forward = addr.parse().map_err(|e| anyhow!("{e} in {key}"))?;
+            return Ok(Endpoint {
+                protocol,
+                listen: Ipv4Addr::UNSPECIFIED.to_string(),
+                forward: Some(forward),
+            });
+        }
+        return Ok(Endpoint {
+            protocol,
+            listen: addr,
+            forward: None,
+        });
+    }
+    let protocol = match mapping.protocol.as_deref() {
+        Some("tcp") => "tcp",
+        Some("udp") => "udp",
+        Some(v) => return Err(anyhow!("invalid protocol `{v}` in {key}")),
+        None => return Err(anyhow!("missing field `protocol` in {key}")),
+    };
+    if mapping.upnp.unwrap_or(global_upnp) {
+        let forward = mapping.forward.as_ref().ok_or(anyhow!(
+            "missing field `forward` in {key}, or set `upnp` to false"
+        ))?;
+        let forward = forward
+            .parse()
+            .map_err(|e| anyhow!("{e} `{forward}` in {key}"))?;
+        // Only the IP address is used, the port is assigned by the gateway.
+        let listen = match &mapping.listen {
+            Some(v) => v
+                .parse::<IpAddr>()
+                .or_else(|_| v.parse::<SocketAddr>().map(|v| v.ip()))
+                .map_err(|e| anyhow!("{e} `{v}` in {key}"))?,
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+        return Ok(Endpoint {
+            protocol,
+            listen: listen.to_string(),
+            forward: Some(forward),
+        });
+    }
+    if mapping.forward.is_some() {
+        return Err(anyhow!("`forward` requires UPnP in {key}"));
+    }
+    let listen = mapping
+        .listen
+        .as_ref()
+        .ok_or(anyhow!("missing field `listen` in {key}"))?;
+    listen
+        .parse::<SocketAddr>()
+        .map_err(|e| anyhow!("{e} `{listen}` in {key}"))?;
+    Ok(Endpoint {
+        protocol,
+        listen: listen.clone(),
+        forward: None,
+    })
+}
+
+/// Returns the scheme and the local address of a url-keyed mapping.
+fn parse_url(key: &str) -> Result<(String, String)> {
+    let url = Url::parse(key).map_err(|e| anyhow!("{e} in {key}"))?;
+    let ip = url.host().ok_or(anyhow!("{EmptyHost} in {key}"))?;
+    let port = url.port().ok_or(anyhow!("{InvalidPort} in {key}"))?;
+    match url.scheme() {
+        "tcp" | "tcp+upnp" | "upnp+tcp" | "udp" | "udp+upnp" | "upnp+udp" => {
+            Ok((url.scheme().to_string(), format!("{ip}:{port}")))
+        }
+        _ => Err(anyhow!("{ErrSchemeType} {} in {key}", url.scheme())),
+    }
+}
+
+pub async fn run(cfg: Config) -> Result<Registry> {
+    // Watcher list.
+    let (watcher_map, errors) = map_watchers(&cfg, None);
+    if let Some((_, e)) = errors.into_iter().next() {
+        return Err(e);
+    }
+    if let Err(e) = start(watcher_map.values()).await {
+        stop(watcher_map.values()).await;
+        return Err(e);
+    }
+    let mut registry = Registry {
+        cfg,
+        watchers: watcher_map,
+        upnp: None,
+        tasks: HashMap::new(),
+        added: HashMap::new(),
+    };
+    // Nothing is left running if any mapping fails.
+    if let Err(e) = registry.spawn_all().await {
+        registry.close().await;
+        return Err(e);
+    }
+    Ok(registry)
+}
+
+/// Start a mapping and notify the watchers when the mapped address is updated.
+async fn spawn(
+    key: String,
+    endpoint: Endpoint,
+    watchers: Watchers,
+    cfg: &Config,
+    upnp: &mut Option<Arc<Upnp>>,
+) -> Result<Task> {
+    let mut local_addr = endpoint.listen;
+    let mut pm: Option<(Arc<Upnp>, PortMap)> = None;
+    if let Some(forward) = endpoint.forward {
+        if upnp.is_none() {
+            *upnp = Some(Arc::new(Upnp::new().await?));
+        }
+        let upnp = upnp.as_ref().unwrap();
+        let protocol = if endpoint.protocol == "tcp" { TCP } else { UDP };
+        let map = upnp.add_port(protocol, forward).await?;
+        local_addr = map.local_addr(local_addr.parse()?);
+        pm = Some((upnp.clone(), map));
+    }
+    let (tx, mut rx) = channel(1);
+    let mapper = if endpoint.protocol == "tcp" {
+        let option = cfg.tcp_options(&key);
+        Mapper::new_tcp(key, local_addr, &option, tx).await
+    } else {
+        let option = cfg.udp_options(&key);
+        Mapper::new_udp(key, local_addr, &option, tx).await
+    };
+    let mut mapper = match mapper {
+        Ok(mapper) => mapper,
+        Err(e) => {
+            if let Some((upnp, pm)) = pm.as_mut() {
+                let _ = upnp.remove_port(pm).await;
+            }
+            return Err(e);
+        }
+    };
+    let (close_tx, mut close) = oneshot::channel();
+    let (watchers_tx, mut watchers_rx) = watch::channel(Arc::new(watchers));
+    let (commands_tx, mut commands) = mpsc::channel(8);
+    let status = Arc::new(Mutex::new(Status {
+        name: mapper.name().to_string(),
+        protocol: mapper.protocol,
+        local_addr: mapper.local_addr(),
+        public: None,
+        upnp: pm.as_ref().map(|(_, pm)| UpnpStatus {
+            forward: pm.forward_addr,
+            external_port: pm.external_port,
+        }),
+        watchers: Vec::new(),
+    }));
+    let task_status = status.clone();
+    let handle = tokio::spawn(async move {
+        let mut watchers = watchers_rx.borrow_and_update().clone();
+        let mut states: Vec<WatcherStatus> = watchers
+            .iter()
+            .map(|(w, md)| WatcherStatus::new(w, md))
+            .collect();
+        let mut current: Option<XorMappedAddress> = None;
+        loop {
+            tokio::select! {
+                Some(addr) = rx.recv() => {
+                    if let Some((upnp, pm)) = pm.as_mut() {
+                        if let Err(e) = upnp.renew_port(pm).await {
+                            error!(mapper = mapper.name(), upnp="renew", "{e}");
                         }
-                        let changed = mapper.changed(&addr);
-                        if changed {
-                            let scheme = mapper.protocol;
-                            if let Some((upnp, pm)) = pm.as_ref() {
-                                info!(
-                                    mapper = mapper.name(),
-                                    "{scheme}://{} <-- upnp://{}:{} --> {scheme}://{}",
-                                    pm.forward_addr,
-                                    upnp.external_ip()
-                                        .await
-                                        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
-                                    pm.external_port,
-                                    addr
-                                );
-                            } else {
-                                info!(
-                                    mapper = mapper.name(),
-                                    "{scheme}://{} <--> {scheme}://{}",
-                                    mapper.local_addr(),
-                                    addr
-                                );
-                            }
+                    }
+                    let changed = mapper.changed(&addr);
+                    if changed {
+                        let scheme = mapper.protocol;
+                        if let Some((upnp, pm)) = pm.as_ref() {
+                            info!(
+                                mapper = mapper.name(),
+                                "{scheme}://{} <-- upnp://{}:{} --> {scheme}://{}",
+                                pm.forward_addr,
+                                upnp.external_ip()
+                                    .await
+                                    .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                                pm.external_port,
+                                addr
+                            );
+                        } else {
+                            info!(
+                                mapper = mapper.name(),
+                                "{scheme}://{} <--> {scheme}://{}",
+                                mapper.local_addr(),
+                                addr
+                            );
                         }
-                        if changed || failed >= 0 {
-                            let i = if changed { 0 } else { failed as usize };
-                            failed = -1;
-                            for (j, (watcher, md)) in watchers[i..].iter().enumerate() {
-                                if let Err(e) = watcher.new_address(&addr, md).await {
-                                    failed = (i + j) as isize;
-                                    error!(
-                                        mapper = mapper.name(),
-                                        watcher = watcher.kind(),
-                                        name = &md.name,
-                                        "{e}"
-                                    );
-                                    break;
-                                }
-                            }
+                        states.iter_mut().for_each(|v| v.pending = true);
+                    }
+                    current = Some(addr);
+                },
+                Some(command) = commands.recv() => match command {
+                    Command::Refresh => mapper.refresh(),
+                    Command::Notify => states.iter_mut().for_each(|v| v.pending = true),
+                },
+                Ok(()) = watchers_rx.changed() => {
+                    let new = watchers_rx.borrow_and_update().clone();
+                    // Notify the new watchers of the current mapped address,
+                    // and keep the state of the ones which are still in the list.
+                    states = new
+                        .iter()
+                        .map(|(w, md)| {
+                            watchers
+                                .iter()
+                                .position(|(v, m)| Arc::ptr_eq(v, w) && m == md)
+                                .map_or_else(|| WatcherStatus::new(w, md), |j| states[j].clone())
+                        })
+                        .collect();
+                    watchers = new;
+                },
+                _ = &mut close => {
+                    if let Some((upnp, pm)) = pm.as_mut() {
+                        let _ = upnp.remove_port(pm).await;
+                    }
+                    mapper.close();
+                    for (watcher, md) in watchers.iter() {
+                        if let Err(e) = watcher.closed(mapper.name(), md).await {
+                            error!(
+                                mapper = mapper.name(),
+                                watcher = watcher.kind(),
+                                name = &md.name,
+                                "{e}"
+                            );
                         }
-                    },
-                    _ = close.recv() => {
-                        if let Some((upnp, pm)) = pm.as_mut() {
-                            let _ = upnp.remove_port(pm).await;
+                    }
+                    break;
+                },
+            }
+            if let Some(addr) = current
+                .as_ref()
+                .filter(|_| states.iter().any(|v| v.pending))
+            {
+                let event = Event {
+                    mapper: mapper.name(),
+                    protocol: mapper.protocol,
+                    addr,
+                    old_addr: mapper.previous,
+                    local_addr: mapper.local_addr(),
+                    upnp_port: pm.as_ref().map(|(_, pm)| pm.external_port),
+                };
+                for ((watcher, md), state) in watchers.iter().zip(states.iter_mut()) {
+                    if !state.pending {
+                        continue;
+                    }
+                    match watcher.new_address(&event, md).await {
+                        Ok(()) => {
+                            state.pending = false;
+                            state.error = None;
                         }
-                        mapper.close();
-                        break;
-                    },
+                        Err(e) => {
+                            error!(
+                                mapper = mapper.name(),
+                                watcher = watcher.kind(),
+                                name = &md.name,
+                                "{e}"
+                            );
+                            state.error = Some(e.to_string());
+                            // Retrying won't help, move on to the next watcher.
+                            if e.is::<PermanentError>() {
+                                state.pending = false;
+                                continue;
+                            }
+                            break;
+                        }
+                    }
                 }
             }
-        }));
+            let mut status = task_status.lock().unwrap();
+            status.public = mapper.public.clone();
+            status.watchers = states.clone();
+        }
+    });
+    Ok(Task {
+        close: close_tx,
+        watchers: watchers_tx,
+        commands: commands_tx,
+        status,
+        handle,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A watcher which counts how many times it is stopped.
+    #[derive(Default)]
+    struct Probe {
+        stopped: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl Watcher for Probe {
+        fn kind(&self) -> &'static str {
+            "probe"
+        }
+
+        fn name(&self) -> &str {
+            "probe"
+        }
+
+        async fn new_address(&self, _: &Event<'_>, _: &Metadata) -> Result<()> {
+            Ok(())
+        }
+
+        async fn validate(&self, _: &Metadata) -> Result<()> {
+            Ok(())
+        }
+
+        async fn stop(&self) {
+            self.stopped.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Returns a configuration of the named mappings, which don't touch the network.
+    fn config(map: serde_json::Value) -> Config {
+        serde_json::from_value(json!({
+            "map": map,
+            "upnp": false,
+            "udp": {"stun": ["127.0.0.1:9"], "interval": 3600},
+            "webhook": {"hook": {"url": "http://127.0.0.1:9/hook"}},
+        }))
+        .unwrap()
+    }
+
+    fn mapping(name: &str, listen: &str) -> serde_json::Value {
+        json!({
+            "name": name,
+            "protocol": "udp",
+            "listen": listen,
+            "watchers": [{"name": "hook", "value": ""}],
+        })
+    }
+
+    fn local_addr(registry: &Registry, name: &str) -> SocketAddr {
+        registry.tasks[name].status.lock().unwrap().local_addr
+    }
+
+    #[tokio::test]
+    async fn reload_unchanged() {
+        let cfg = config(json!([mapping("a", "127.0.0.1:0")]));
+        let mut registry = run(cfg.clone()).await.unwrap();
+        let addr = local_addr(&registry, "a");
+        let hook = registry.watchers["hook"].clone();
+        registry.reload(cfg).await.unwrap();
+        // The mapping would be bound to another port if it was restarted.
+        assert_eq!(local_addr(&registry, "a"), addr);
+        assert!(Arc::ptr_eq(&registry.watchers["hook"], &hook));
+        registry.close().await;
+    }
+
+    #[tokio::test]
+    async fn reload_changed_endpoint() {
+        let mut registry = run(config(json!([mapping("a", "127.0.0.1:0")])))
+            .await
+            .unwrap();
+        registry
+            .reload(config(json!([mapping("a", "127.0.0.2:0")])))
+            .await
+            .unwrap();
+        assert_eq!(local_addr(&registry, "a").ip().to_string(), "127.0.0.2");
+        registry.close().await;
+    }
+
+    #[tokio::test]
+    async fn reload_invalid() {
+        let mut registry = run(config(json!([mapping("a", "127.0.0.1:0")])))
+            .await
+            .unwrap();
+        let addr = local_addr(&registry, "a");
+        // A running watcher which is not in the new configuration.
+        let probe = Arc::new(Probe::default());
+        registry.watchers.insert("probe".to_string(), probe.clone());
+        let mut invalid = mapping("b", "127.0.0.1:0");
+        invalid["watchers"][0]["name"] = json!("missing");
+        let e = registry
+            .reload(config(json!([mapping("a", "127.0.0.2:0"), invalid])))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("no watcher named `missing`"), "{e}");
+        assert_eq!(probe.stopped.load(Ordering::SeqCst), 0);
+        assert_eq!(local_addr(&registry, "a"), addr);
+        assert!(!registry.tasks.contains_key("b"));
+        registry.close().await;
+        assert_eq!(probe.stopped.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reload_spawn_error() {
+        let mut registry = run(config(json!([mapping("a", "127.0.0.1:0")])))
+            .await
+            .unwrap();
+        let used = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let used = used.local_addr().unwrap().to_string();
+        let e = registry
+            .reload(config(json!([
+                mapping("a", "127.0.0.1:0"),
+                mapping("b", &used),
+                mapping("c", "127.0.0.1:0"),
+            ])))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("failed to start mapping b"), "{e}");
+        // The rest of the configuration is applied.
+        assert!(!registry.tasks.contains_key("b"));
+        assert!(registry.tasks.contains_key("c"));
+        registry.close().await;
+    }
+
+    /// Returns a plugin configuration whose program writes its pid to the file,
+    /// and answers every request with the reply.
+    fn plugin(pid: &std::path::Path, reply: &str) -> serde_json::Value {
+        let id = r#"id=$(echo "$l" | sed 's/^{"id":\([0-9]*\).*/\1/')"#;
+        json!({
+            "path": "sh",
+            "args": [
+                "-c",
+                format!("echo $$ > {}; while read l; do {id}; {reply}; done", pid.display())
+            ]
+        })
+    }
+
+    /// Returns whether the process with the pid in the file is still running.
+    async fn running(pid: &std::path::Path) -> bool {
+        let pid = std::fs::read_to_string(pid).unwrap();
+        for _ in 0..20 {
+            // A killed process may not be reaped yet.
+            match std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())) {
+                Ok(stat) if !stat.contains(") Z ") => {}
+                _ => return false,
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        true
+    }
+
+    #[tokio::test]
+    async fn reload_validate_error() {
+        let pid = mock::temp_path("reload");
+        let mut registry = run(config(json!([mapping("a", "127.0.0.1:0")])))
+            .await
+            .unwrap();
+        let addr = local_addr(&registry, "a");
+        let mut a = mapping("a", "127.0.0.1:0");
+        a["watchers"] = json!([{"name": "hook", "value": ""}, {"name": "p", "value": ""}]);
+        let mut cfg = config(json!([a]));
+        cfg.plugin = serde_json::from_value(json!({
+            "p": plugin(&pid, r#"echo "{\"id\":$id,\"error\":\"bad value\"}""#)
+        }))
+        .unwrap();
+        let e = registry.reload(cfg).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "bad value in a, keep running with the old configuration"
+        );
+        assert!(!registry.watchers.contains_key("p"));
+        assert_eq!(registry.tasks["a"].watchers.borrow().len(), 1);
+        assert_eq!(local_addr(&registry, "a"), addr);
+        assert!(!running(&pid).await);
+        let _ = std::fs::remove_file(&pid);
+        registry.close().await;
+    }
+
+    #[tokio::test]
+    async fn run_error_stops_watchers() {
+        let pid = mock::temp_path("run");
+        let mut b = mapping("b", "127.0.0.1:0");
+        // The plugin is started to validate the first watcher, then the second one is missing.
+        b["watchers"] = json!([{"name": "p", "value": ""}, {"name": "missing", "value": ""}]);
+        let mut cfg = config(json!([mapping("a", "127.0.0.1:0"), b]));
+        cfg.plugin = serde_json::from_value(json!({
+            "p": plugin(&pid, r#"echo "{\"id\":$id}""#)
+        }))
+        .unwrap();
+        let e = run(cfg).await.err().unwrap();
+        assert!(e.to_string().contains("no watcher named `missing`"), "{e}");
+        assert!(!running(&pid).await);
+        let _ = std::fs::remove_file(&pid);
+    }
+
+    #[tokio::test]
+    async fn kind_qualifier() {
+        let mut cfg = config(json!([]));
+        cfg.cf = serde_json::from_value(json!({"main": {"token": "t"}})).unwrap();
+        let (watcher_map, errors) = map_watchers(&cfg, None);
+        assert!(errors.is_empty());
+        let md = |name: &str| -> Metadata {
+            serde_json::from_value(
+                json!({"name": name, "value": "", "domain": "example.com", "type": "TXT"}),
+            )
+            .unwrap()
+        };
+        let watchers = resolve_watchers("a", &[md("cf:main")], &watcher_map)
+            .await
+            .unwrap();
+        assert_eq!(watchers[0].0.kind(), "cf");
+        let e = resolve_watchers("a", &[md("main"), md("dnspod:main")], &watcher_map)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "watcher `main` is cf, not dnspod in a at index 1"
+        );
+    }
+
+    /// Resolve the endpoint of the only mapping in the map.
+    fn resolve_endpoint(map: serde_json::Value, upnp: Option<bool>) -> Result<Endpoint> {
+        let cfg: Config = serde_json::from_value(json!({"map": map})).unwrap();
+        let (key, mapping) = cfg.map.iter().next().unwrap();
+        endpoint(key, mapping, upnp)
+    }
+
+    #[test]
+    fn url_endpoint() {
+        let e = resolve_endpoint(json!({"tcp://0.0.0.0:6666": []}), Some(false)).unwrap();
+        assert_eq!((e.protocol, e.listen.as_str()), ("tcp", "0.0.0.0:6666"));
+        assert_eq!(e.forward, None);
+        // UPnP is enabled globally by default.
+        let e = resolve_endpoint(json!({"udp://192.168.1.2:6666": []}), None).unwrap();
+        assert_eq!((e.protocol, e.listen.as_str()), ("udp", "0.0.0.0"));
+        assert_eq!(e.forward, Some("192.168.1.2:6666".parse().unwrap()));
+        // The scheme enables UPnP for the mapping only.
+        let e = resolve_endpoint(json!({"upnp+tcp://192.168.1.2:6666": []}), Some(false)).unwrap();
+        assert_eq!(e.forward, Some("192.168.1.2:6666".parse().unwrap()));
+
+        for url in [
+            "http://0.0.0.0:6666",
+            "tcp://0.0.0.0",
+            "tcp://:6666",
+            "0.0.0.0:6666",
+            "udp://0.0.0.0:70000",
+        ] {
+            assert!(
+                resolve_endpoint(json!({ url: [] }), Some(false)).is_err(),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    fn named_endpoint() {
+        let named = |v: serde_json::Value, upnp| {
+            let mut mapping = json!({"name": "a"});
+            mapping
+                .as_object_mut()
+                .unwrap()
+                .extend(v.as_object().unwrap().clone());
+            resolve_endpoint(json!([mapping]), upnp)
+        };
+        let e = named(
+            json!({"protocol": "udp", "listen": "0.0.0.0:6666"}),
+            Some(false),
+        )
+        .unwrap();
+        assert_eq!((e.protocol, e.listen.as_str()), ("udp", "0.0.0.0:6666"));
+        // The mapping option overrides the global one.
+        let e = named(
+            json!({"protocol": "tcp", "upnp": true, "listen": "10.0.0.1", "forward": "10.0.0.1:80"}),
+            Some(false),
+        )
+        .unwrap();
+        assert_eq!((e.protocol, e.listen.as_str()), ("tcp", "10.0.0.1"));
+        assert_eq!(e.forward, Some("10.0.0.1:80".parse().unwrap()));
+        let e = named(
+            json!({"protocol": "tcp", "upnp": false, "listen": "0.0.0.0:80"}),
+            None,
+        )
+        .unwrap();
+        assert_eq!(e.forward, None);
+
+        for (v, upnp) in [
+            (
+                json!({"protocol": "sctp", "listen": "0.0.0.0:6666"}),
+                Some(false),
+            ),
+            (json!({"protocol": "tcp"}), Some(false)),
+            (json!({"protocol": "tcp", "listen": "0.0.0.0"}), Some(false)),
+            (
+                json!({"protocol": "tcp", "listen": "0.0.0.0:80", "forward": "10.0.0.1:80"}),
+                Some(false),
+            ),
+            (json!({"protocol": "tcp", "listen": "0.0.0.0:80"}), None),
+        ] {
+            assert!(named(v.clone(), upnp).is_err(), "{v}");
+        }
+    }
+
+    #[tokio::test]
+    async fn check_collects_errors() {
+        let spawned = mock::temp_path("check");
+        let cfg: Config = serde_json::from_value(json!({
+            "map": {
+                "tcp://0.0.0.0": [{"name": "hook", "value": ""}],
+                "udp://0.0.0.0:6666": [{"name": "missing", "value": ""}],
+                "udp://0.0.0.0:7777": [{"name": "plugin", "value": ""}]
+            },
+            "webhook": {"hook": {"url": "http://127.0.0.1:9/hook"}},
+            "plugin": {"plugin": {
+                "path": "sh",
+                "args": ["-c", format!("touch {}; cat", spawned.display())]
+            }}
+        }))
+        .unwrap();
+        let (errors, skipped) = check(&cfg).await;
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors.iter().any(|e| e.contains("tcp://0.0.0.0")));
+        assert!(errors
+            .iter()
+            .any(|e| e.contains("no watcher named `missing`")));
+        // The plugin is neither started nor asked to validate the metadata.
+        assert_eq!(skipped, ["plugin.plugin"]);
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!spawned.exists());
     }
-    Ok(Closer { tasks, close })
 }
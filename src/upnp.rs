@@ -144,8 +144,8 @@ impl PortMap {
     /// Returns the local socket binding address.
     /// The external port is used because the NAT gateway usually
     /// keep the source port unchanged.
-    pub fn local_addr(&self) -> String {
-        format!("0.0.0.0:{}", self.external_port)
+    pub fn local_addr(&self, ip: IpAddr) -> String {
+        SocketAddr::new(ip, self.external_port).to_string()
     }
 }
 
@@ -1,5 +1,5 @@
 use crate::config::Metadata;
-use crate::watcher::{dns, format_value, Watcher};
+use crate::watcher::{dns, format_value, Event, Watcher};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use hex::ToHex;
@@ -7,13 +7,28 @@ use reqwest::header::HeaderMap;
 use ring::{digest, hmac, rand};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use stun::xoraddr::XorMappedAddress;
 use time::format_description::well_known::{iso8601, Iso8601};
 use time::OffsetDateTime;
 use tracing::debug;
 use url::ParseError::EmptyHost;
 use url::Url;
 
+/// All supported DNS record types.
+const TYPES: [&str; 12] = [
+    "A",
+    "AAAA",
+    "CAA",
+    "CNAME",
+    "FORWARD_URL",
+    "HTTPS",
+    "MX",
+    "NS",
+    "REDIRECT_URL",
+    "SRV",
+    "SVCB",
+    "TXT",
+];
+
 /// [AliDNS](https://www.alidns.com).
 pub struct AliDns {
     /// Instance name.
@@ -44,6 +59,9 @@ struct DescribeSubDomainRecordsRequest {
     subdomain: String,
     #[serde(rename = "Type")]
     record_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "Line")]
+    line: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -57,13 +75,18 @@ struct DescribeSubDomainRecordsResponse {
 #[derive(Deserialize)]
 struct DomainRecords {
     #[serde(rename = "Record")]
-    record: Vec<RecordId>,
+    record: Vec<RecordInfo>,
 }
 
 #[derive(Deserialize)]
-struct RecordId {
+struct RecordInfo {
     #[serde(rename = "RecordId")]
     record_id: String,
+    #[serde(rename = "Weight")]
+    weight: Option<u32>,
+    /// `ENABLE` or `DISABLE`.
+    #[serde(rename = "Status")]
+    status: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -82,6 +105,9 @@ struct Record {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "TTL")]
     ttl: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "Line")]
+    line: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -102,6 +128,22 @@ struct UpdateDomainRecordRequest {
 
 type AddDomainRecordRequest = Record;
 
+#[derive(Serialize)]
+struct UpdateDNSSLBWeightRequest {
+    #[serde(rename = "RecordId")]
+    record_id: String,
+    #[serde(rename = "Weight")]
+    weight: u32,
+}
+
+#[derive(Serialize)]
+struct SetDomainRecordStatusRequest {
+    #[serde(rename = "RecordId")]
+    record_id: String,
+    #[serde(rename = "Status")]
+    status: &'static str,
+}
+
 impl BaseResponse {
     fn success(&self) -> Result<()> {
         self.code
@@ -241,13 +283,19 @@ impl AliDns {
         Ok(headers)
     }
 
-    /// Returns the first record id that matches the given domain and record type.
+    /// Returns the first record that matches the given domain, record type and line.
     /// See <https://help.aliyun.com/zh/dns/api-alidns-2015-01-09-describesubdomainrecords>.
-    async fn get_record_id(&self, domain: String, record_type: String) -> Result<Option<String>> {
+    async fn get_record(
+        &self,
+        domain: String,
+        record_type: String,
+        line: Option<String>,
+    ) -> Result<Option<RecordInfo>> {
         let client = reqwest::Client::new();
         let payload = DescribeSubDomainRecordsRequest {
             subdomain: domain,
             record_type,
+            line,
         };
         let mut req = client.post(&self.url).query(&payload).build()?;
         let resp: DescribeSubDomainRecordsResponse = client
@@ -258,11 +306,9 @@ impl AliDns {
             .json()
             .await?;
         resp.common.success()?;
-        Ok(if let Some(list) = resp.domain_records {
-            list.record.get(0).map(|v| v.record_id.to_owned())
-        } else {
-            None
-        })
+        Ok(resp
+            .domain_records
+            .and_then(|list| list.record.into_iter().next()))
     }
 
     /// Create a new record.
@@ -318,6 +364,42 @@ impl AliDns {
         );
         Ok(record_id)
     }
+
+    /// Change the weight of a record.
+    /// Weighted round-robin must be enabled for the subdomain.
+    /// See <https://help.aliyun.com/zh/dns/api-alidns-2015-01-09-updatednsslbweight>.
+    async fn update_weight(&self, record_id: String, weight: u32) -> Result<()> {
+        debug!(record_id, weight, name = self.name(), "update weight");
+        let client = reqwest::Client::new();
+        let payload = UpdateDNSSLBWeightRequest { record_id, weight };
+        let mut req = client.post(&self.url).query(&payload).build()?;
+        let resp: BaseResponse = client
+            .post(req.url().to_owned())
+            .headers(self.headers("UpdateDNSSLBWeight", req.url_mut())?)
+            .send()
+            .await?
+            .json()
+            .await?;
+        resp.success()
+    }
+
+    /// Enable or disable a record.
+    /// See <https://help.aliyun.com/zh/dns/api-alidns-2015-01-09-setdomainrecordstatus>.
+    async fn set_status(&self, record_id: String, enabled: bool) -> Result<()> {
+        let status = if enabled { "Enable" } else { "Disable" };
+        debug!(record_id, status, name = self.name(), "set status");
+        let client = reqwest::Client::new();
+        let payload = SetDomainRecordStatusRequest { record_id, status };
+        let mut req = client.post(&self.url).query(&payload).build()?;
+        let resp: BaseResponse = client
+            .post(req.url().to_owned())
+            .headers(self.headers("SetDomainRecordStatus", req.url_mut())?)
+            .send()
+            .await?
+            .json()
+            .await?;
+        resp.success()
+    }
 }
 
 #[async_trait]
@@ -330,33 +412,132 @@ impl Watcher for AliDns {
         self.name.as_str()
     }
 
-    async fn new_address(&self, addr: &XorMappedAddress, md: &Metadata) -> Result<()> {
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
         let domain = md.domain.as_ref().unwrap();
         let record_type = md.kind.clone().unwrap();
         let (domain_name, subdomain) = dns::split_domain_name(domain).unwrap();
-        let mut record_id: Option<String> = md.rid.clone();
-        if record_id.is_none() {
-            record_id = self
-                .get_record_id(domain.to_owned(), record_type.clone())
-                .await?;
-        }
+        // The current weight and status are unknown if the record id is given.
+        let current = match &md.rid {
+            Some(rid) => Some(RecordInfo {
+                record_id: rid.to_owned(),
+                weight: None,
+                status: None,
+            }),
+            None => {
+                self.get_record(domain.to_owned(), record_type.clone(), md.line.clone())
+                    .await?
+            }
+        };
         let record = Record {
             domain_name,
             rr: dns::subdomain(subdomain),
             record_type,
-            value: format_value(&md.value, addr),
+            value: format_value(&md.value, event.addr),
             priority: md.priority,
             ttl: md.ttl,
+            line: md.line.clone(),
         };
-        if let Some(rid) = record_id {
-            self.update_record(rid, record).await?;
+        let (record_id, weight, status) = if let Some(current) = current {
+            let record_id = self.update_record(current.record_id, record).await?;
+            (record_id, current.weight, current.status)
         } else {
-            self.create_record(record).await?;
+            (self.create_record(record).await?, None, None)
+        };
+        if let Some(weight) = md.weight.filter(|&v| Some(v) != weight) {
+            self.update_weight(record_id.clone(), weight).await?;
+        }
+        if let Some(enabled) = md.enabled {
+            if status.map(|v| v.eq_ignore_ascii_case("enable")) != Some(enabled) {
+                self.set_status(record_id, enabled).await?;
+            }
         }
         Ok(())
     }
 
-    fn validate(&self, md: &Metadata) -> Result<()> {
-        dns::validate(md)
+    async fn validate(&self, md: &Metadata) -> Result<()> {
+        dns::validate(md)?;
+        let record_type = md.kind.as_ref().unwrap().to_uppercase();
+        if !TYPES.contains(&record_type.as_str()) {
+            return Err(anyhow!("unsupported record type `{record_type}`"));
+        }
+        if let Some(weight) = md.weight {
+            if !(1..=100).contains(&weight) {
+                return Err(anyhow!("`weight` must be between 1 and 100"));
+            }
+        }
+        dns::validate_value(record_type.as_str(), &md.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+
+    const RECORD: &str = r#"{"RequestId":"1","DomainRecords":{"Record":[{"RecordId":"7","Weight":10,"Status":"ENABLE"}]}}"#;
+    const NO_RECORD: &str = r#"{"RequestId":"1","DomainRecords":{"Record":[]}}"#;
+    const RECORD_ID: &str = r#"{"RequestId":"2","RecordId":"7"}"#;
+    const OK: &str = r#"{"RequestId":"3"}"#;
+
+    /// Update the record with the given weight and status, and return the actions sent.
+    async fn actions(weight: u32, enabled: bool, responses: Vec<&str>) -> Vec<String> {
+        let (url, mut requests) =
+            mock::serve(responses.iter().map(|v| Response::new(200, v)).collect()).await;
+        let alidns = AliDns::new(
+            "alidns".to_string(),
+            "id".to_string(),
+            "key".to_string(),
+            Some(url),
+        )
+        .unwrap();
+        let md = serde_json::from_value(serde_json::json!({
+            "name": "alidns",
+            "value": "{ip}",
+            "domain": "www.example.com",
+            "type": "A",
+            "weight": weight,
+            "enabled": enabled,
+        }))
+        .unwrap();
+        mock::new_address(&alidns, &md).await.unwrap();
+        let mut actions = Vec::new();
+        while let Ok(req) = requests.try_recv() {
+            actions.push(req.header("x-acs-action").unwrap().to_string());
+        }
+        actions
+    }
+
+    #[tokio::test]
+    async fn weight_and_status_unchanged() {
+        assert_eq!(
+            actions(10, true, vec![RECORD, RECORD_ID]).await,
+            ["DescribeSubDomainRecords", "UpdateDomainRecord"]
+        );
+    }
+
+    #[tokio::test]
+    async fn weight_and_status_changed() {
+        assert_eq!(
+            actions(20, false, vec![RECORD, RECORD_ID, OK, OK]).await,
+            [
+                "DescribeSubDomainRecords",
+                "UpdateDomainRecord",
+                "UpdateDNSSLBWeight",
+                "SetDomainRecordStatus"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn weight_and_status_created() {
+        assert_eq!(
+            actions(10, true, vec![NO_RECORD, RECORD_ID, OK, OK]).await,
+            [
+                "DescribeSubDomainRecords",
+                "AddDomainRecord",
+                "UpdateDNSSLBWeight",
+                "SetDomainRecordStatus"
+            ]
+        );
     }
 }
@@ -1,12 +1,16 @@
 use crate::config::Metadata;
-use crate::watcher::{dns, format_value, Watcher};
+use crate::watcher::{dns, format_value, Event, Watcher};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use reqwest::header::HeaderMap;
+use reqwest::{RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::fmt::Debug;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::Mutex;
 use stun::xoraddr::XorMappedAddress;
-use tracing::debug;
+use tracing::{debug, error, info, warn};
 
 /// Base request url.
 const URL: &str = "https://api.cloudflare.com/client/v4/zones";
@@ -23,8 +27,44 @@ pub struct Cloudflare {
     /// API token.
     /// See <https://developers.cloudflare.com/fundamentals/api/get-started/create-token>.
     token: String,
+    /// Zone id used for all records instead of looking it up by domain name.
+    zone_id: Option<String>,
+    /// Whether to delete the duplicates of a record matched by comment or tags.
+    /// Each deleted record is logged with its id.
+    cleanup: bool,
+    /// Base request url.
+    url: String,
+    /// Cached zone ids keyed by domain name.
+    zones: Mutex<HashMap<String, String>>,
+    /// Cached record ids.
+    records: Mutex<HashMap<RecordKey, String>>,
 }
 
+/// Identifies the record maintained by a watcher metadata.
+#[derive(Hash, PartialEq, Eq)]
+struct RecordKey {
+    zone_id: String,
+    /// Full domain name.
+    name: String,
+    record_type: String,
+    /// Only match records with this comment.
+    comment: Option<String>,
+    /// Only match records with all of these tags.
+    tags: Vec<String>,
+}
+
+/// The requested zone or record doesn't exist (anymore).
+#[derive(Debug)]
+struct NotFound(anyhow::Error);
+
+impl Display for NotFound {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
 #[derive(Deserialize)]
 struct Response<T> {
     errors: Vec<Error>,
@@ -43,7 +83,7 @@ impl<T> Response<T> {
         if self.success {
             Ok(())
         } else {
-            Err(if let Some(err) = self.errors.get(0) {
+            Err(if let Some(err) = self.errors.first() {
                 anyhow!("error {}: {}", err.code, err.message)
             } else {
                 anyhow!("unknown error")
@@ -67,6 +107,10 @@ struct Record {
     priority: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     ttl: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -104,9 +148,35 @@ struct URI {
     weight: u16,
 }
 
+/// Send different DNS records depending on the record type.
+macro_rules! send_record {
+    ($self:expr, $zone_id:expr, $record_id:expr, $kind:expr, {$($s:pat => $record:expr),*}) => {
+        match $kind {
+            $(
+                $s => {
+                    let record = $record;
+                    if let Some(rid) = $record_id {
+                        $self.update_record($zone_id, rid, record).await?
+                    } else {
+                        $self.create_record($zone_id, record).await?
+                    }
+                }
+            )*
+        }
+    };
+}
+
 impl Cloudflare {
-    pub fn new(name: String, token: String) -> Self {
-        Self { name, token }
+    pub fn new(name: String, token: String, zone_id: Option<String>, cleanup: bool) -> Self {
+        Self {
+            name,
+            token,
+            zone_id,
+            cleanup,
+            url: URL.to_string(),
+            zones: Mutex::new(HashMap::new()),
+            records: Mutex::new(HashMap::new()),
+        }
     }
 
     fn headers(&self, json: bool) -> HeaderMap {
@@ -124,50 +194,140 @@ impl Cloudflare {
         headers
     }
 
+    /// Send the request and decode the response.
+    /// A `404 Not Found` status is reported as a `NotFound` error.
+    async fn send<T: DeserializeOwned>(&self, req: RequestBuilder) -> Result<Response<T>> {
+        let resp = req.send().await?;
+        let status = resp.status();
+        if status == StatusCode::NOT_FOUND {
+            // The body may not be JSON, e.g. when the zone is gone.
+            let e = match resp.json::<Response<T>>().await {
+                Ok(resp) => resp.success().err().unwrap_or(anyhow!("{status}")),
+                Err(_) => anyhow!("{status}"),
+            };
+            return Err(NotFound(e).into());
+        }
+        let resp: Response<T> = resp.json().await?;
+        resp.success()?;
+        Ok(resp)
+    }
+
+    /// Returns the zone id of the domain name, looking it up if it is not cached.
+    async fn zone_id(&self, domain: &String) -> Result<String> {
+        if let Some(zone_id) = &self.zone_id {
+            return Ok(zone_id.to_owned());
+        }
+        if let Some(zone_id) = self.zones.lock().unwrap().get(domain) {
+            return Ok(zone_id.to_owned());
+        }
+        let zone_id = self.get_zone_id(domain).await?;
+        self.zones
+            .lock()
+            .unwrap()
+            .insert(domain.to_owned(), zone_id.to_owned());
+        Ok(zone_id)
+    }
+
+    /// Returns the record id matching the key, looking it up if it is not cached.
+    async fn record_id(&self, key: &RecordKey) -> Result<Option<String>> {
+        if let Some(record_id) = self.records.lock().unwrap().get(key) {
+            return Ok(Some(record_id.to_owned()));
+        }
+        self.get_record_id(key).await
+    }
+
+    /// Drop the cached ids of the domain.
+    fn invalidate(&self, domain: &String) {
+        if let Some((domain_name, _)) = dns::split_domain_name(domain) {
+            self.zones.lock().unwrap().remove(&domain_name);
+        }
+        self.records
+            .lock()
+            .unwrap()
+            .retain(|key, _| &key.name != domain);
+    }
+
     /// Returns the first zone id that matches the given domain name.
     /// See <https://developers.cloudflare.com/api/operations/zones-get>.
     async fn get_zone_id(&self, domain: &String) -> Result<String> {
         let client = reqwest::Client::new();
-        let resp: Response<Vec<Id>> = client
-            .get(URL)
-            .headers(self.headers(false))
-            .query(&[("name", domain)])
-            .send()
-            .await?
-            .json()
+        let resp: Response<Vec<Id>> = self
+            .send(
+                client
+                    .get(&self.url)
+                    .headers(self.headers(false))
+                    .query(&[("name", domain)]),
+            )
             .await?;
-        resp.success()?;
         if let Some(zones) = resp.result {
-            if let Some(zone) = zones.get(0) {
+            if let Some(zone) = zones.first() {
                 return Ok(zone.id.to_owned());
             }
         }
         Err(anyhow!("{domain} is not found in your account"))
     }
 
-    /// Returns the first record id that matches the given domain and record type.
+    /// Returns the first record id that matches the given domain, record type,
+    /// and the comment and tags if present.
+    /// The other matches are duplicates, which are deleted if `cleanup` is enabled.
     /// See <https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-list-dns-records>.
-    async fn get_record_id(
-        &self,
-        zone_id: &String,
-        domain: &String,
-        record_type: &String,
-    ) -> Result<Option<String>> {
+    async fn get_record_id(&self, key: &RecordKey) -> Result<Option<String>> {
         let client = reqwest::Client::new();
-        let resp: Response<Vec<Id>> = client
-            .get(format!("{URL}/{zone_id}/dns_records"))
-            .headers(self.headers(false))
-            .query(&[("name", domain), ("type", record_type)])
-            .send()
-            .await?
-            .json()
+        let mut query = vec![("name", key.name.as_str()), ("type", &key.record_type)];
+        if let Some(comment) = &key.comment {
+            query.push(("comment.exact", comment));
+        }
+        if !key.tags.is_empty() {
+            query.push(("tag_match", "all"));
+            query.extend(key.tags.iter().map(|tag| ("tag.exact", tag.as_str())));
+        }
+        let resp: Response<Vec<Id>> = self
+            .send(
+                client
+                    .get(format!("{}/{}/dns_records", self.url, key.zone_id))
+                    .headers(self.headers(false))
+                    .query(&query),
+            )
             .await?;
-        resp.success()?;
-        Ok(if let Some(list) = resp.result {
-            list.get(0).map(|v| v.id.to_owned())
-        } else {
-            None
-        })
+        let list = resp.result.unwrap_or_default();
+        let owned = key.comment.is_some() || !key.tags.is_empty();
+        for duplicate in list.iter().skip(1).filter(|_| owned) {
+            if !self.cleanup {
+                warn!(
+                    name = self.name(),
+                    record_id = duplicate.id,
+                    "duplicate {} record {}",
+                    key.record_type,
+                    key.name
+                );
+                continue;
+            }
+            match self.delete_record(&key.zone_id, &duplicate.id).await {
+                Ok(_) => info!(
+                    name = self.name(),
+                    record_id = duplicate.id,
+                    "deleted duplicate {} record {}",
+                    key.record_type,
+                    key.name
+                ),
+                Err(e) => error!(name = self.name(), record_id = duplicate.id, "{e}"),
+            }
+        }
+        Ok(list.first().map(|v| v.id.to_owned()))
+    }
+
+    /// Delete a DNS record.
+    /// See <https://developers.cloudflare.com/api/operations/dns-records-for-a-zone-delete-dns-record>.
+    async fn delete_record(&self, zone_id: &str, record_id: &str) -> Result<()> {
+        let client = reqwest::Client::new();
+        let _: Response<Id> = self
+            .send(
+                client
+                    .delete(format!("{}/{zone_id}/dns_records/{record_id}", self.url))
+                    .headers(self.headers(false)),
+            )
+            .await?;
+        Ok(())
     }
 
     /// Create a new DNS record for a zone.
@@ -180,15 +340,14 @@ impl Cloudflare {
         debug!(zone_id, name = self.name(), "create {:?}", record);
         let client = reqwest::Client::new();
         let bytes = serde_json::to_vec(&record)?;
-        let resp: Response<Id> = client
-            .post(format!("{URL}/{zone_id}/dns_records"))
-            .headers(self.headers(true))
-            .body(bytes)
-            .send()
-            .await?
-            .json()
+        let resp: Response<Id> = self
+            .send(
+                client
+                    .post(format!("{}/{zone_id}/dns_records", self.url))
+                    .headers(self.headers(true))
+                    .body(bytes),
+            )
             .await?;
-        resp.success()?;
         let record_id = resp
             .result
             .ok_or(anyhow!("record id not found in response"))?
@@ -219,15 +378,14 @@ impl Cloudflare {
         );
         let client = reqwest::Client::new();
         let bytes = serde_json::to_vec(&record)?;
-        let resp: Response<Id> = client
-            .patch(format!("{URL}/{zone_id}/dns_records/{record_id}"))
-            .headers(self.headers(true))
-            .body(bytes)
-            .send()
-            .await?
-            .json()
+        let resp: Response<Id> = self
+            .send(
+                client
+                    .patch(format!("{}/{zone_id}/dns_records/{record_id}", self.url))
+                    .headers(self.headers(true))
+                    .body(bytes),
+            )
             .await?;
-        resp.success()?;
         let record_id = resp
             .result
             .ok_or(anyhow!("record id not found in response"))?
@@ -240,44 +398,23 @@ impl Cloudflare {
         );
         Ok(record_id)
     }
-}
-
-/// Send different DNS records depending on the record type.
-macro_rules! send_record {
-    ($self:expr, $zone_id:expr, $record_id:expr, $kind:expr, {$($s:pat => $record:expr),*}) => {
-        match $kind {
-            $(
-                $s => {
-                    let record = $record;
-                    if let Some(rid) = $record_id {
-                        $self.update_record($zone_id, rid, record).await?;
-                    } else {
-                        $self.create_record($zone_id, record).await?;
-                    }
-                }
-            )*
-        }
-    };
-}
-
-#[async_trait]
-impl Watcher for Cloudflare {
-    fn kind(&self) -> &'static str {
-        "cf"
-    }
 
-    fn name(&self) -> &str {
-        self.name.as_str()
-    }
-
-    async fn new_address(&self, addr: &XorMappedAddress, md: &Metadata) -> Result<()> {
+    /// Create or update the record described by the metadata.
+    async fn update(&self, addr: &XorMappedAddress, md: &Metadata) -> Result<()> {
         let domain = md.domain.as_ref().unwrap();
         let record_type = md.kind.clone().unwrap();
         let (domain_name, subdomain) = dns::split_domain_name(domain).unwrap();
-        let zone_id = self.get_zone_id(&domain_name).await?;
+        let zone_id = self.zone_id(&domain_name).await?;
+        let key = RecordKey {
+            zone_id: zone_id.clone(),
+            name: domain.to_owned(),
+            record_type: record_type.clone(),
+            comment: md.comment.clone(),
+            tags: md.tags.clone(),
+        };
         let mut record_id: Option<String> = md.rid.clone();
         if record_id.is_none() {
-            record_id = self.get_record_id(&zone_id, domain, &record_type).await?;
+            record_id = self.record_id(&key).await?;
         }
         let base = Record {
             name: dns::subdomain(subdomain),
@@ -285,9 +422,11 @@ impl Watcher for Cloudflare {
             record_type,
             priority: md.priority,
             ttl: md.ttl,
+            comment: md.comment.clone(),
+            tags: md.tags.clone(),
         };
         let value = format_value(&md.value, addr);
-        send_record!(self, zone_id, record_id, base.record_type.to_uppercase().as_str(), {
+        let record_id = send_record!(self, zone_id, record_id, base.record_type.to_uppercase().as_str(), {
             "HTTPS" | "SVCB" => CustomRecord {
                 base,
                 data: SVCB::try_from((md.priority.unwrap(), value))?,
@@ -308,10 +447,36 @@ impl Watcher for Cloudflare {
                 content: value,
             }
         });
+        if md.rid.is_none() {
+            self.records.lock().unwrap().insert(key, record_id);
+        }
         Ok(())
     }
+}
+
+#[async_trait]
+impl Watcher for Cloudflare {
+    fn kind(&self) -> &'static str {
+        "cf"
+    }
 
-    fn validate(&self, md: &Metadata) -> Result<()> {
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        match self.update(event.addr, md).await {
+            Err(e) if e.is::<NotFound>() => {
+                // The cached zone or record was deleted, look it up again.
+                debug!(name = self.name(), "{e}, invalidating cache");
+                self.invalidate(md.domain.as_ref().unwrap());
+                self.update(event.addr, md).await
+            }
+            result => result,
+        }
+    }
+
+    async fn validate(&self, md: &Metadata) -> Result<()> {
         dns::validate(md)?;
         let record_type = md.kind.as_ref().unwrap().to_uppercase();
         if !TYPES.contains(&record_type.as_str()) {
@@ -380,3 +545,188 @@ impl TryFrom<(u16, String)> for SVCB {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+
+    const LIST: &str = r#"{"success":true,"errors":[],"result":[{"id":"r1"},{"id":"r2"}]}"#;
+    const RECORD: &str = r#"{"success":true,"errors":[],"result":{"id":"r1"}}"#;
+    const ZONES: &str = r#"{"success":true,"errors":[],"result":[{"id":"z"}]}"#;
+    const NOT_FOUND: &str = r#"{"success":false,"errors":[{"code":81044,"message":"Record does not exist."}],"result":null}"#;
+
+    fn a_record() -> Metadata {
+        serde_json::from_value(serde_json::json!({
+            "name": "cf",
+            "value": "{ip}",
+            "domain": "www.example.com",
+            "type": "A",
+        }))
+        .unwrap()
+    }
+
+    /// Update a plain record twice, looking up the zone id by domain name.
+    async fn update_twice(responses: Vec<Response>) -> Vec<mock::Request> {
+        let len = responses.len();
+        let (url, mut requests) = mock::serve(responses).await;
+        let mut cf = Cloudflare::new("cf".to_string(), "t".to_string(), None, false);
+        cf.url = url;
+        let md = a_record();
+        for ip in ["2.2.2.2", "3.3.3.3"] {
+            let addr = XorMappedAddress {
+                ip: ip.parse().unwrap(),
+                port: 2222,
+            };
+            let event = Event {
+                addr: &addr,
+                ..mock::event(None)
+            };
+            cf.new_address(&event, &md).await.unwrap();
+        }
+        let mut received = Vec::new();
+        for _ in 0..len {
+            received.push(requests.recv().await.unwrap());
+        }
+        assert!(requests.try_recv().is_err());
+        received
+    }
+
+    async fn update(cleanup: bool, responses: Vec<Response>) -> Vec<mock::Request> {
+        let len = responses.len();
+        let (url, mut requests) = mock::serve(responses).await;
+        let mut cf = Cloudflare::new(
+            "cf".to_string(),
+            "t".to_string(),
+            Some("z".to_string()),
+            cleanup,
+        );
+        cf.url = url;
+        let md = serde_json::from_value(serde_json::json!({
+            "name": "cf",
+            "value": "{ip}",
+            "domain": "www.example.com",
+            "type": "A",
+            "comment": "nat2",
+            "tags": ["owner:nat2", "site:a"],
+        }))
+        .unwrap();
+        cf.validate(&md).await.unwrap();
+        mock::new_address(&cf, &md).await.unwrap();
+        let mut received = Vec::new();
+        for _ in 0..len {
+            received.push(requests.recv().await.unwrap());
+        }
+        assert!(requests.try_recv().is_err());
+        received
+    }
+
+    #[tokio::test]
+    async fn duplicates_kept() {
+        let requests = update(
+            false,
+            vec![Response::new(200, LIST), Response::new(200, RECORD)],
+        )
+        .await;
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(
+            requests[0].path,
+            "/z/dns_records?name=www.example.com&type=A&comment.exact=nat2\
+             &tag_match=all&tag.exact=owner%3Anat2&tag.exact=site%3Aa"
+        );
+        assert_eq!(requests[1].method, "PATCH");
+        assert_eq!(requests[1].path, "/z/dns_records/r1");
+        assert_eq!(requests[1].json()["content"], "2.2.2.2");
+        assert!(requests.iter().all(|v| v.method != "DELETE"));
+    }
+
+    #[tokio::test]
+    async fn duplicates_deleted() {
+        let requests = update(
+            true,
+            vec![
+                Response::new(200, LIST),
+                Response::new(200, r#"{"success":true,"errors":[],"result":{"id":"r2"}}"#),
+                Response::new(200, RECORD),
+            ],
+        )
+        .await;
+        assert_eq!(requests[1].method, "DELETE");
+        assert_eq!(requests[1].path, "/z/dns_records/r2");
+        assert_eq!(requests[2].method, "PATCH");
+        assert_eq!(requests[2].path, "/z/dns_records/r1");
+    }
+
+    #[tokio::test]
+    async fn ids_cached() {
+        let requests = update_twice(vec![
+            Response::new(200, ZONES),
+            Response::new(200, LIST),
+            Response::new(200, RECORD),
+            Response::new(200, RECORD),
+        ])
+        .await;
+        assert_eq!(requests[0].path, "/?name=example.com");
+        assert_eq!(
+            requests[1].path,
+            "/z/dns_records?name=www.example.com&type=A"
+        );
+        // The second update goes straight to the cached record.
+        assert_eq!(requests[2].method, "PATCH");
+        assert_eq!(requests[2].path, "/z/dns_records/r1");
+        assert_eq!(requests[3].method, "PATCH");
+        assert_eq!(requests[3].path, "/z/dns_records/r1");
+        assert_eq!(requests[3].json()["content"], "3.3.3.3");
+    }
+
+    #[tokio::test]
+    async fn not_found_invalidated() {
+        let requests = update_twice(vec![
+            Response::new(200, ZONES),
+            Response::new(200, LIST),
+            Response::new(200, RECORD),
+            // The record is deleted before the second update.
+            Response::new(404, NOT_FOUND),
+            Response::new(200, ZONES),
+            Response::new(
+                200,
+                r#"{"success":true,"errors":[],"result":[{"id":"r3"}]}"#,
+            ),
+            Response::new(200, r#"{"success":true,"errors":[],"result":{"id":"r3"}}"#),
+        ])
+        .await;
+        assert_eq!(requests[3].path, "/z/dns_records/r1");
+        assert_eq!(requests[4].path, "/?name=example.com");
+        assert_eq!(
+            requests[5].path,
+            "/z/dns_records?name=www.example.com&type=A"
+        );
+        assert_eq!(requests[6].method, "PATCH");
+        assert_eq!(requests[6].path, "/z/dns_records/r3");
+        assert_eq!(requests[6].json()["content"], "3.3.3.3");
+    }
+
+    #[tokio::test]
+    async fn not_found_retried_once() {
+        let (url, mut requests) = mock::serve(vec![
+            Response::new(200, ZONES),
+            Response::new(200, LIST),
+            Response::new(404, NOT_FOUND),
+            Response::new(200, ZONES),
+            Response::new(200, LIST),
+            Response::new(404, NOT_FOUND),
+        ])
+        .await;
+        let mut cf = Cloudflare::new("cf".to_string(), "t".to_string(), None, false);
+        cf.url = url;
+        let md = a_record();
+        let e = mock::new_address(&cf, &md).await.unwrap_err();
+        assert!(e.is::<NotFound>(), "{e}");
+        for _ in 0..6 {
+            requests.recv().await.unwrap();
+        }
+        assert!(requests.try_recv().is_err());
+        // Nothing stale is left in the cache.
+        assert!(cf.records.lock().unwrap().is_empty());
+    }
+}
@@ -1,17 +1,19 @@
 use crate::config::Metadata;
-use crate::watcher::{dns, format_value, Watcher};
+use crate::watcher::{dns, format_value, Event, Watcher};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use hex::ToHex;
 use reqwest::header::HeaderMap;
 use ring::{digest, hmac};
 use serde::{Deserialize, Serialize};
-use stun::xoraddr::XorMappedAddress;
 use time::OffsetDateTime;
 use tracing::debug;
 
 const HOST: &str = "dnspod.tencentcloudapi.com";
 
+/// Default record line.
+const DEFAULT_LINE: &str = "默认";
+
 /// [DNSPod](https://www.dnspod.cn).
 pub struct DnsPod {
     /// Instance name.
@@ -32,6 +34,9 @@ struct DescribeRecordListRequest {
     subdomain: String,
     #[serde(rename = "RecordType")]
     record_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "RecordLine")]
+    record_line: Option<String>,
 }
 
 #[derive(Serialize, Debug)]
@@ -45,13 +50,19 @@ struct Record {
     #[serde(rename = "Value")]
     value: String,
     #[serde(rename = "RecordLine")]
-    record_line: &'static str,
+    record_line: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "MX")]
     mx: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "TTL")]
     ttl: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "Weight")]
+    weight: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "Status")]
+    status: Option<&'static str>,
 }
 
 type CreateRecordRequest = Record;
@@ -98,6 +109,8 @@ struct RecordResponse {
 struct DescribeRecordListResponseItem {
     #[serde(rename = "RecordId")]
     record_id: u64,
+    #[serde(rename = "Line")]
+    line: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -191,14 +204,16 @@ impl DnsPod {
         headers
     }
 
-    /// Returns the first record id that matches the given domain and record type.
+    /// Returns the first record that matches the given domain, record type and line.
+    /// Records of any line match if the line is not given.
     /// See <https://cloud.tencent.com/document/api/1427/56166>.
-    async fn get_record_id(
+    async fn get_record(
         &self,
         domain: String,
         subdomain: String,
         record_type: String,
-    ) -> Result<Option<u64>> {
+        record_line: Option<String>,
+    ) -> Result<Option<DescribeRecordListResponseItem>> {
         let client = reqwest::Client::new();
         let payload = DescribeRecordListRequest {
             domain,
@@ -208,6 +223,7 @@ impl DnsPod {
                 subdomain
             },
             record_type,
+            record_line,
         };
         let bytes = serde_json::to_vec(&payload)?;
         let resp: Response<DescribeRecordListResponse> = client
@@ -228,11 +244,10 @@ impl DnsPod {
                 _ => Err(anyhow!("{}: {}", v.code, v.message))
             })
             .transpose()?;
-        Ok(if let Some(list) = resp.response.record_list {
-            list.get(0).map(|v| v.record_id)
-        } else {
-            None
-        })
+        Ok(resp
+            .response
+            .record_list
+            .and_then(|list| list.into_iter().next()))
     }
 
     /// Create a new record.
@@ -304,24 +319,38 @@ impl Watcher for DnsPod {
         self.name.as_str()
     }
 
-    async fn new_address(&self, addr: &XorMappedAddress, md: &Metadata) -> Result<()> {
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
         let domain = md.domain.as_ref().unwrap();
         let record_type = md.kind.clone().unwrap();
         let (domain, subdomain) = dns::split_domain_name(domain).unwrap();
+        let mut record_line = md.line.clone();
         let mut record_id: Option<u64> = md.rid.as_ref().map(|v| v.parse().unwrap());
         if record_id.is_none() {
-            record_id = self
-                .get_record_id(domain.clone(), subdomain.clone(), record_type.clone())
-                .await?;
+            if let Some(record) = self
+                .get_record(
+                    domain.clone(),
+                    subdomain.clone(),
+                    record_type.clone(),
+                    record_line.clone(),
+                )
+                .await?
+            {
+                record_id = Some(record.record_id);
+                // Keep the line of the existing record.
+                record_line = record_line.or(record.line);
+            }
         }
+        let record_line = record_line.unwrap_or(DEFAULT_LINE.to_string());
         let record = Record {
             domain,
             subdomain: dns::subdomain(subdomain),
             record_type,
-            value: format_value(&md.value, addr),
-            record_line: "默认",
+            value: format_value(&md.value, event.addr),
+            record_line,
             mx: md.priority,
             ttl: md.ttl,
+            weight: md.weight,
+            status: md.enabled.map(|v| if v { "ENABLE" } else { "DISABLE" }),
         };
         if let Some(rid) = record_id {
             self.update_record(rid, record).await?;
@@ -331,11 +360,144 @@ impl Watcher for DnsPod {
         Ok(())
     }
 
-    fn validate(&self, md: &Metadata) -> Result<()> {
+    async fn validate(&self, md: &Metadata) -> Result<()> {
         dns::validate(md)?;
         if let Some(rid) = &md.rid {
             rid.parse::<u64>()?;
         }
+        if let Some(weight) = md.weight {
+            if weight > 100 {
+                return Err(anyhow!("`weight` must be between 0 and 100"));
+            }
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+
+    /// Update the record described by the metadata, and return the requests.
+    async fn update(md: serde_json::Value, responses: Vec<Response>) -> Vec<mock::Request> {
+        let len = responses.len();
+        let (url, mut requests) = mock::serve(responses).await;
+        let mut dnspod = DnsPod::new("dnspod".to_string(), "id".to_string(), "key".to_string());
+        dnspod.url = url;
+        let md = serde_json::from_value(md).unwrap();
+        dnspod.validate(&md).await.unwrap();
+        mock::new_address(&dnspod, &md).await.unwrap();
+        let mut received = Vec::new();
+        for _ in 0..len {
+            received.push(requests.recv().await.unwrap());
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn default_line() {
+        let requests = update(
+            serde_json::json!({
+                "name": "dnspod",
+                "value": "{ip}",
+                "domain": "www.example.com",
+                "type": "A",
+            }),
+            vec![
+                Response::new(
+                    200,
+                    r#"{"Response":{"RequestId":"1","RecordList":[{"RecordId":7}]}}"#,
+                ),
+                Response::new(200, r#"{"Response":{"RequestId":"2","RecordId":7}}"#),
+            ],
+        )
+        .await;
+        assert_eq!(
+            requests[0].header("x-tc-action"),
+            Some("DescribeRecordList")
+        );
+        assert_eq!(
+            requests[0].json(),
+            serde_json::json!({
+                "Domain": "example.com",
+                "Subdomain": "www",
+                "RecordType": "A",
+            })
+        );
+        assert_eq!(requests[1].header("x-tc-action"), Some("ModifyRecord"));
+        assert_eq!(
+            requests[1].json(),
+            serde_json::json!({
+                "RecordId": 7,
+                "Domain": "example.com",
+                "SubDomain": "www",
+                "RecordType": "A",
+                "Value": "2.2.2.2",
+                "RecordLine": "默认",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn existing_line_kept() {
+        let requests = update(
+            serde_json::json!({
+                "name": "dnspod",
+                "value": "{ip}",
+                "domain": "www.example.com",
+                "type": "A",
+            }),
+            vec![
+                Response::new(
+                    200,
+                    r#"{"Response":{"RequestId":"1","RecordList":[{"RecordId":7,"Line":"电信"}]}}"#,
+                ),
+                Response::new(200, r#"{"Response":{"RequestId":"2","RecordId":7}}"#),
+            ],
+        )
+        .await;
+        assert!(requests[0].json().get("RecordLine").is_none());
+        assert_eq!(requests[1].header("x-tc-action"), Some("ModifyRecord"));
+        assert_eq!(requests[1].json()["RecordId"], 7);
+        assert_eq!(requests[1].json()["RecordLine"], "电信");
+    }
+
+    #[tokio::test]
+    async fn line_weight_status() {
+        let requests = update(
+            serde_json::json!({
+                "name": "dnspod",
+                "value": "{ip}",
+                "domain": "example.com",
+                "type": "A",
+                "line": "电信",
+                "weight": 10,
+                "enabled": false,
+            }),
+            vec![
+                Response::new(
+                    200,
+                    r#"{"Response":{"RequestId":"1","Error":{"Code":"ResourceNotFound.NoDataOfRecord","Message":"no record"}}}"#,
+                ),
+                Response::new(200, r#"{"Response":{"RequestId":"2","RecordId":8}}"#),
+            ],
+        )
+        .await;
+        assert_eq!(requests[0].json()["Subdomain"], "@");
+        assert_eq!(requests[0].json()["RecordLine"], "电信");
+        assert_eq!(requests[1].header("x-tc-action"), Some("CreateRecord"));
+        assert_eq!(
+            requests[1].json(),
+            serde_json::json!({
+                "Domain": "example.com",
+                "SubDomain": "@",
+                "RecordType": "A",
+                "Value": "2.2.2.2",
+                "RecordLine": "电信",
+                "Weight": 10,
+                "Status": "DISABLE",
+            })
+        );
+    }
+}
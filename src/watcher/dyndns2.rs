@@ -0,0 +1,148 @@
+use crate::config::Metadata;
+use crate::watcher::{format_value, Event, PermanentError, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tracing::debug;
+use url::Url;
+
+/// User agent sent to the update server.
+/// Most providers block requests without a descriptive user agent.
+const USER_AGENT: &str = concat!("nat2/", env!("CARGO_PKG_VERSION"));
+
+/// Dynamic DNS update protocol.
+/// See <https://help.dyn.com/remote-access-api/perform-update>.
+pub struct DynDns2 {
+    /// Instance name.
+    name: String,
+    /// Update url, e.g. `https://dynupdate.no-ip.com/nic/update`.
+    url: Url,
+    /// Similar to username.
+    username: String,
+    /// Similar to password.
+    password: String,
+}
+
+impl DynDns2 {
+    pub fn new(name: String, url: String, username: String, password: String) -> Result<Self> {
+        let url = Url::parse(url.as_str())?;
+        Ok(Self {
+            name,
+            url,
+            username,
+            password,
+        })
+    }
+}
+
+/// Interpret the return code of an update request.
+/// See <https://help.dyn.com/remote-access-api/return-codes>.
+fn parse_response(body: &str) -> Result<()> {
+    let line = body.lines().next().unwrap_or("").trim();
+    let code = line.split_whitespace().next().unwrap_or("");
+    let reason = match code {
+        "good" | "nochg" => return Ok(()),
+        "dnserr" | "911" => return Err(anyhow!("server error `{line}`, retry later")),
+        "badauth" => "bad authorization (username or password)",
+        "!donator" => "the feature is not available to the user",
+        "notfqdn" => "the hostname is not a fully-qualified domain name",
+        "nohost" => "the hostname does not exist in this user account",
+        "numhost" => "too many hosts specified in an update",
+        "abuse" => "the hostname is blocked for update abuse",
+        "badagent" => "the user agent was not sent or has been blocked",
+        _ => return Err(anyhow!("unknown response `{line}`")),
+    };
+    Err(PermanentError::new(format!("{code}: {reason}")).into())
+}
+
+#[async_trait]
+impl Watcher for DynDns2 {
+    fn kind(&self) -> &'static str {
+        "dyndns2"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let hostname = md.domain.as_ref().unwrap();
+        let myip = if md.value.is_empty() {
+            event.addr.ip.to_string()
+        } else {
+            format_value(&md.value, event.addr)
+        };
+        let mut url = self.url.clone();
+        url.query_pairs_mut()
+            .append_pair("hostname", hostname)
+            .append_pair("myip", myip.as_str());
+        let client = reqwest::Client::new();
+        let resp = client
+            .get(url)
+            .header("User-Agent", USER_AGENT)
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await?;
+        let status = resp.status();
+        let body = resp.text().await?;
+        debug!(
+            code = status.as_str(),
+            name = self.name(),
+            "{}",
+            body.trim()
+        );
+        parse_response(body.as_str()).map_err(|e| {
+            if status.is_success() || e.is::<PermanentError>() {
+                e
+            } else {
+                anyhow!("server returned {status}")
+            }
+        })
+    }
+
+    async fn validate(&self, md: &Metadata) -> Result<()> {
+        md.domain
+            .as_ref()
+            .ok_or(anyhow!("missing field `domain`"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How a return code is handled.
+    #[derive(Debug, PartialEq)]
+    enum Outcome {
+        Ok,
+        Retry,
+        Permanent,
+    }
+
+    #[test]
+    fn return_codes() {
+        for (body, expected) in [
+            ("good 1.2.3.4", Outcome::Ok),
+            ("nochg 1.2.3.4\n", Outcome::Ok),
+            ("good 1.2.3.4\nnochg 1.2.3.4", Outcome::Ok),
+            ("911", Outcome::Retry),
+            ("dnserr", Outcome::Retry),
+            ("", Outcome::Retry),
+            ("<html>Bad Gateway</html>", Outcome::Retry),
+            ("badauth", Outcome::Permanent),
+            ("nohost", Outcome::Permanent),
+            ("notfqdn", Outcome::Permanent),
+            ("abuse", Outcome::Permanent),
+            ("badagent", Outcome::Permanent),
+            ("!donator", Outcome::Permanent),
+            ("numhost", Outcome::Permanent),
+        ] {
+            let outcome = match parse_response(body) {
+                Ok(_) => Outcome::Ok,
+                Err(e) if e.is::<PermanentError>() => Outcome::Permanent,
+                Err(_) => Outcome::Retry,
+            };
+            assert_eq!(outcome, expected, "{body:?}");
+        }
+    }
+}
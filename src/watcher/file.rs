@@ -0,0 +1,229 @@
+use crate::config::Metadata;
+use crate::watcher::{format_event, Event, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::debug;
+
+/// Sequence number of the temporary files.
+static TMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Render a template file and write it to the target path.
+pub struct File {
+    /// Instance name.
+    name: String,
+    /// Path to template file.
+    template: Option<PathBuf>,
+    /// Path to target file.
+    path: PathBuf,
+    /// Command to run after the file is written, e.g. `["nginx", "-s", "reload"]`.
+    command: Vec<String>,
+}
+
+impl File {
+    pub fn new(
+        name: String,
+        template: Option<String>,
+        path: String,
+        command: Vec<String>,
+    ) -> Result<Self> {
+        let path = PathBuf::from(path);
+        if path.file_name().is_none() {
+            return Err(anyhow!("invalid file path `{}`", path.display()));
+        }
+        Ok(Self {
+            name,
+            template: template.map(PathBuf::from),
+            path,
+            command,
+        })
+    }
+
+    /// Write the content to a temporary file next to the target, then rename
+    /// it to the target, so readers never see a partially written file.
+    async fn write(&self, content: &[u8]) -> Result<()> {
+        // Concurrent writes, even from other instances, each get their own temporary file.
+        let mut tmp = self.path.clone();
+        tmp.set_file_name(format!(
+            ".{}.nat2.{}.{}.tmp",
+            self.path.file_name().unwrap().to_string_lossy(),
+            std::process::id(),
+            TMP_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = async {
+            let mut f = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp)
+                .await?;
+            f.write_all(content).await?;
+            f.sync_all().await?;
+            drop(f);
+            // Keep the permissions of the existing file.
+            if let Ok(metadata) = fs::metadata(&self.path).await {
+                fs::set_permissions(&tmp, metadata.permissions()).await?;
+            }
+            fs::rename(&tmp, &self.path).await?;
+            // Persist the rename itself, which is recorded in the directory.
+            #[cfg(unix)]
+            {
+                let dir = match self.path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => std::path::Path::new("."),
+                };
+                fs::File::open(dir).await?.sync_all().await?;
+            }
+            Ok::<_, std::io::Error>(())
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp).await;
+        }
+        Ok(result?)
+    }
+
+    /// Run the reload command.
+    async fn reload(&self) -> Result<()> {
+        let Some((program, args)) = self.command.split_first() else {
+            return Ok(());
+        };
+        let mut command = Command::new(program);
+        command.args(args);
+        debug!(name = self.name(), "starting new {:?}", command);
+        let output = command.output().await?;
+        debug!(
+            name = self.name(),
+            "process finished with {}", output.status
+        );
+        if output.status.success() {
+            Ok(())
+        } else if !output.stderr.is_empty() {
+            Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr)))
+        } else {
+            Err(anyhow!("process finished with {}", output.status))
+        }
+    }
+}
+
+#[async_trait]
+impl Watcher for File {
+    fn kind(&self) -> &'static str {
+        "file"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let template = if !md.value.is_empty() {
+            md.value.clone()
+        } else {
+            // The template is read every time, so changes are picked up without restart.
+            let path = self.template.as_ref().unwrap();
+            fs::read_to_string(path)
+                .await
+                .map_err(|e| anyhow!("{e}: {}", path.display()))?
+        };
+        self.write(format_event(&template, event).as_bytes())
+            .await
+            .map_err(|e| anyhow!("{e}: {}", self.path.display()))?;
+        debug!(
+            name = self.name(),
+            "{} written successfully",
+            self.path.display()
+        );
+        self.reload().await
+    }
+
+    async fn validate(&self, md: &Metadata) -> Result<()> {
+        if md.value.is_empty() && self.template.is_none() {
+            return Err(anyhow!("missing field `template`"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    /// Returns an empty directory in the temp directory which is unique to the test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = mock::temp_path(name);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    /// Returns the names of the temporary files left in the directory.
+    fn tmp_files(dir: &Path) -> Vec<String> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|v| v.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|v| v.ends_with(".tmp"))
+            .collect()
+    }
+
+    fn file(dir: &Path, target: &str) -> File {
+        let copy = dir.join("copy");
+        File::new(
+            "f".to_string(),
+            Some(dir.join("template").display().to_string()),
+            dir.join(target).display().to_string(),
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                format!("cat {} > {}", dir.join(target).display(), copy.display()),
+            ],
+        )
+        .unwrap()
+    }
+
+    async fn run(file: &File) -> Result<()> {
+        mock::new_address(file, &mock::metadata("f", "")).await
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn written() {
+        let dir = temp_dir("file-written");
+        std::fs::write(dir.join("template"), "server {ip}:{port};").unwrap();
+        let target = dir.join("target");
+        std::fs::write(&target, "old").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600)).unwrap();
+        run(&file(&dir, "target")).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&target).unwrap(),
+            "server 2.2.2.2:2222;"
+        );
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // The command sees the new content.
+        assert_eq!(
+            std::fs::read_to_string(dir.join("copy")).unwrap(),
+            "server 2.2.2.2:2222;"
+        );
+        assert!(tmp_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn rename_failed() {
+        let dir = temp_dir("file-rename");
+        std::fs::write(dir.join("template"), "server {ip}:{port};").unwrap();
+        // A file can't replace a non-empty directory.
+        std::fs::create_dir_all(dir.join("target").join("sub")).unwrap();
+        assert!(run(&file(&dir, "target")).await.is_err());
+        assert!(tmp_files(&dir).is_empty());
+        assert!(!dir.join("copy").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
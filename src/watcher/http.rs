@@ -1,12 +1,11 @@
 use crate::config::Metadata;
-use crate::watcher::{format_value, Watcher};
+use crate::watcher::{format_value, Event, Watcher};
 use anyhow::Result;
 use async_trait::async_trait;
 use reqwest::header::HeaderMap;
 use reqwest::Method;
 use std::collections::HashMap;
 use std::str::FromStr;
-use stun::xoraddr::XorMappedAddress;
 use tracing::debug;
 use url::Url;
 
@@ -58,7 +57,7 @@ impl Watcher for Http {
         self.name.as_str()
     }
 
-    async fn new_address(&self, addr: &XorMappedAddress, md: &Metadata) -> Result<()> {
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
         let client = reqwest::Client::new();
         let mut body = Some(md.value.clone());
         if md.value.is_empty() {
@@ -66,13 +65,13 @@ impl Watcher for Http {
         }
         let mut url = self.url.clone();
         if let Some(query) = url.query() {
-            url.set_query(Some(format_value(&query.to_string(), addr).as_str()));
+            url.set_query(Some(format_value(&query.to_string(), event.addr).as_str()));
         }
         let mut req = client
             .request(self.method.clone(), url)
             .headers(self.headers.clone());
         if let Some(body) = body {
-            req = req.body(format_value(&body, addr));
+            req = req.body(format_value(&body, event.addr));
         }
         let resp = req.send().await?.error_for_status()?;
         debug!(
@@ -83,7 +82,7 @@ impl Watcher for Http {
         Ok(())
     }
 
-    fn validate(&self, _: &Metadata) -> Result<()> {
+    async fn validate(&self, _: &Metadata) -> Result<()> {
         Ok(())
     }
 }
@@ -0,0 +1,176 @@
+use crate::config::Metadata;
+use crate::watcher::{chat, Event, PermanentError, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use time::OffsetDateTime;
+use tracing::debug;
+use url::Url;
+
+/// [Matrix](https://spec.matrix.org/latest/client-server-api) room.
+pub struct Matrix {
+    /// Instance name.
+    name: String,
+    /// Request url of the room, e.g. `https://matrix.org/_matrix/client/v3/rooms/{room_id}`.
+    url: Url,
+    /// Access token of the sender.
+    token: String,
+    /// Message template.
+    message: Option<String>,
+    /// Counter to make the transaction id unique.
+    txn: AtomicU64,
+    /// The message not sent yet and its transaction id, keyed by the mapper and watcher name.
+    pending: Mutex<HashMap<(String, String), (String, String)>>,
+}
+
+#[derive(Serialize)]
+struct RoomMessage {
+    msgtype: &'static str,
+    body: String,
+}
+
+impl Matrix {
+    pub fn new(
+        name: String,
+        url: String,
+        token: String,
+        room_id: String,
+        message: Option<String>,
+    ) -> Result<Self> {
+        let mut url = Url::parse(url.as_str())?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid homeserver url"))?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3", "rooms", room_id.as_str()]);
+        Ok(Self {
+            name,
+            url,
+            token,
+            message,
+            txn: AtomicU64::new(0),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns a new transaction id.
+    fn txn_id(&self) -> String {
+        format!(
+            "nat2.{}.{}",
+            OffsetDateTime::now_utc().unix_timestamp_nanos(),
+            self.txn.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    /// Returns the url to send a message event.
+    /// See <https://spec.matrix.org/latest/client-server-api/#put_matrixclientv3roomsroomidsendeventtypetxnid>.
+    fn send_url(&self, txn_id: &str) -> Url {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .extend(["send", "m.room.message", txn_id]);
+        url
+    }
+}
+
+#[async_trait]
+impl Watcher for Matrix {
+    fn kind(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let client = reqwest::Client::new();
+        let payload = RoomMessage {
+            msgtype: "m.text",
+            body: chat::message(&self.message, event, md),
+        };
+        // The transaction id of a message is kept until it is sent, so the homeserver
+        // drops the duplicate if a retried request was in fact received before.
+        let key = (event.mapper.to_string(), md.name.clone());
+        let txn_id = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some((body, txn_id)) if *body == payload.body => txn_id.clone(),
+                _ => {
+                    let txn_id = self.txn_id();
+                    pending.insert(key.clone(), (payload.body.clone(), txn_id.clone()));
+                    txn_id
+                }
+            }
+        };
+        let req = client
+            .put(self.send_url(&txn_id))
+            .bearer_auth(&self.token)
+            .json(&payload);
+        let resp = chat::send(self.name(), req).await?;
+        debug!(
+            code = resp.status().as_str(),
+            name = self.name(),
+            "message sent"
+        );
+        let result = chat::check(resp).await;
+        if result
+            .as_ref()
+            .map_or_else(|e| e.is::<PermanentError>(), |_| true)
+        {
+            self.pending.lock().unwrap().remove(&key);
+        }
+        result
+    }
+
+    async fn validate(&self, _: &Metadata) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+
+    #[tokio::test]
+    async fn txn_id_kept_until_sent() {
+        let (url, mut requests) = mock::serve(vec![
+            Response::new(502, ""),
+            Response::new(200, r#"{"event_id":"$1"}"#),
+            Response::new(200, r#"{"event_id":"$2"}"#),
+        ])
+        .await;
+        let matrix = Matrix::new(
+            "m".to_string(),
+            url,
+            "secret".to_string(),
+            "!room:example.org".to_string(),
+            Some("{mapper} {ip}:{port}".to_string()),
+        )
+        .unwrap();
+        let event = mock::event(None);
+        let md = mock::metadata("m", "");
+        assert!(matrix.new_address(&event, &md).await.is_err());
+        matrix.new_address(&event, &md).await.unwrap();
+        matrix.new_address(&event, &md).await.unwrap();
+
+        let first = requests.recv().await.unwrap();
+        assert_eq!(first.method, "PUT");
+        assert!(first
+            .path
+            .starts_with("/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/nat2."));
+        assert_eq!(first.header("authorization"), Some("Bearer secret"));
+        assert_eq!(
+            first.json(),
+            serde_json::json!({"msgtype": "m.text", "body": "tcp://0.0.0.0:6666 2.2.2.2:2222"})
+        );
+        // The retry uses the same transaction id, the next message gets a new one.
+        let retry = requests.recv().await.unwrap();
+        assert_eq!(retry.path, first.path);
+        let next = requests.recv().await.unwrap();
+        assert_ne!(next.path, first.path);
+    }
+}
@@ -1,12 +1,28 @@
 pub mod alidns;
 pub mod cf;
 pub mod dnspod;
+pub mod dyndns2;
+pub mod file;
 pub mod http;
+pub mod matrix;
+pub mod mqtt;
+pub mod plugin;
+pub mod qbittorrent;
 pub mod script;
+pub mod smtp;
+pub mod telegram;
+pub mod transmission;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod webhook;
+pub mod wireguard;
 
 use crate::config;
 use anyhow::Result;
 use async_trait::async_trait;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
 use stun::xoraddr::XorMappedAddress;
 
 /// A `Watcher` watches the update of mapped address.
@@ -19,11 +35,92 @@ pub trait Watcher {
     /// Instance name.
     fn name(&self) -> &str;
     /// Mapped address is updated with new value.
-    async fn new_address(&self, addr: &XorMappedAddress, md: &config::Metadata) -> Result<()>;
+    async fn new_address(&self, event: &Event<'_>, md: &config::Metadata) -> Result<()>;
     /// Verify whether the metadata configuration is legal.
-    fn validate(&self, md: &config::Metadata) -> Result<()>;
+    async fn validate(&self, md: &config::Metadata) -> Result<()>;
+    /// Whether the metadata is only validated after the watcher is started.
+    fn validates_when_started(&self) -> bool {
+        false
+    }
+    /// The mapper is closed and its address is no longer available.
+    async fn closed(&self, _mapper: &str, _md: &config::Metadata) -> Result<()> {
+        Ok(())
+    }
+    /// Acquire the resources used at runtime, such as connections and processes.
+    /// It is called before the watcher is used by the mappers.
+    async fn start(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Release the resources acquired by `start`. It is called when the watcher
+    /// is replaced or removed by a reload, and on exit.
+    async fn stop(&self) {}
+}
+
+/// Details of a mapped address update.
+pub struct Event<'a> {
+    /// Name of the mapper.
+    pub mapper: &'a str,
+    /// `tcp` or `udp`.
+    pub protocol: &'static str,
+    /// New mapped address.
+    pub addr: &'a XorMappedAddress,
+    /// Previous mapped address.
+    /// It is `None` if this is the first address of the mapper.
+    pub old_addr: Option<SocketAddr>,
+    /// Socket binding address of the NAT client.
+    pub local_addr: SocketAddr,
+    /// The mapped external port on the gateway if UPnP is used.
+    pub upnp_port: Option<u16>,
+}
+
+/// Serializable form of `Event`, passed to external programs.
+#[derive(Serialize)]
+pub struct EventData<'a> {
+    pub mapper: &'a str,
+    pub protocol: &'a str,
+    pub ip: String,
+    pub port: u16,
+    pub old_ip: Option<String>,
+    pub old_port: Option<u16>,
+    pub local_addr: String,
+    pub upnp_port: Option<u16>,
 }
 
+impl<'a> From<&'a Event<'a>> for EventData<'a> {
+    fn from(event: &'a Event<'a>) -> Self {
+        Self {
+            mapper: event.mapper,
+            protocol: event.protocol,
+            ip: event.addr.ip.to_string(),
+            port: event.addr.port,
+            old_ip: event.old_addr.map(|v| v.ip().to_string()),
+            old_port: event.old_addr.map(|v| v.port()),
+            local_addr: event.local_addr.to_string(),
+            upnp_port: event.upnp_port,
+        }
+    }
+}
+
+/// An error that will not go away by retrying the same request,
+/// such as bad credentials. The watcher is skipped until the
+/// mapped address changes again.
+#[derive(Debug)]
+pub struct PermanentError(String);
+
+impl PermanentError {
+    pub fn new(msg: impl Into<String>) -> Self {
+        Self(msg.into())
+    }
+}
+
+impl Display for PermanentError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0.as_str())
+    }
+}
+
+impl std::error::Error for PermanentError {}
+
 /// Replace `{ip}` and `{port}` with the actual value.
 pub fn format_value(value: &String, addr: &XorMappedAddress) -> String {
     value
@@ -31,8 +128,23 @@ pub fn format_value(value: &String, addr: &XorMappedAddress) -> String {
         .replace("{port}", addr.port.to_string().as_str())
 }
 
+/// Replace `{ip}` and `{port}` with the new mapped address, `{old_ip}` and `{old_port}`
+/// with the previous one, as well as `{mapper}` and `{protocol}`.
+pub fn format_event(value: &String, event: &Event) -> String {
+    let (old_ip, old_port) = event.old_addr.map_or((String::new(), String::new()), |v| {
+        (v.ip().to_string(), v.port().to_string())
+    });
+    format_value(value, event.addr)
+        .replace("{old_ip}", old_ip.as_str())
+        .replace("{old_port}", old_port.as_str())
+        .replace("{mapper}", event.mapper)
+        .replace("{protocol}", event.protocol)
+}
+
 pub mod dns {
     use anyhow::anyhow;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use stun::xoraddr::XorMappedAddress;
     use url::ParseError::InvalidDomainCharacter;
 
     /// Validate basic DNS metadata.
@@ -53,6 +165,59 @@ pub mod dns {
         Ok(())
     }
 
+    /// Validate the value format of the given record type.
+    /// Placeholders are replaced with an example address of the record family before validation.
+    pub fn validate_value(record_type: &str, value: &String) -> super::Result<()> {
+        let example_addr = XorMappedAddress {
+            ip: match record_type {
+                "AAAA" => Ipv6Addr::LOCALHOST.into(),
+                _ => Ipv4Addr::new(1, 1, 1, 1).into(),
+            },
+            port: 1111,
+        };
+        let value = super::format_value(value, &example_addr);
+        let labels: Vec<&str> = value.split_whitespace().collect();
+        let format = match record_type {
+            "A" => {
+                return value
+                    .trim()
+                    .parse::<Ipv4Addr>()
+                    .map(|_| ())
+                    .map_err(|e| anyhow!("invalid value `{value}`: {e}"));
+            }
+            "AAAA" => {
+                return value
+                    .trim()
+                    .parse::<Ipv6Addr>()
+                    .map(|_| ())
+                    .map_err(|e| anyhow!("invalid value `{value}`: {e}"));
+            }
+            "SRV" if labels.len() != 4 || labels[..3].iter().any(|v| v.parse::<u16>().is_err()) => {
+                "priority weight port target"
+            }
+            // The priority is a separate field, and the alias form (priority 0) only has a target.
+            "HTTPS" | "SVCB"
+                if labels.is_empty()
+                    || labels[0].contains('=')
+                    || labels[1..].iter().any(|v| !is_svc_param(v)) =>
+            {
+                "target [key=value ...]"
+            }
+            "CAA" if labels.len() < 3 || labels[0].parse::<u8>().is_err() => "flags tag value",
+            _ => return Ok(()),
+        };
+        Err(anyhow!("invalid value format (e.g. `{format}`)"))
+    }
+
+    /// Returns whether `s` is a `key[=value]` service parameter of SVCB and HTTPS records.
+    fn is_svc_param(s: &&str) -> bool {
+        let key = s.split_once('=').map_or(*s, |(key, _)| key);
+        !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    }
+
     /// Split domain name into host record and SLD.
     pub fn split_domain_name(domain: &String) -> Option<(String, String)> {
         let mut labels: Vec<_> = domain.split(".").collect();
@@ -76,4 +241,360 @@ pub mod dns {
             s
         }
     }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn value_format() {
+            for (record_type, value, ok) in [
+                ("A", "{ip}", true),
+                ("A", "2.2.2.2", true),
+                ("A", "::1", false),
+                ("AAAA", "{ip}", true),
+                ("AAAA", "2001:db8::1", true),
+                ("AAAA", "2.2.2.2", false),
+                ("SRV", "0 5 {port} mc.example.com", true),
+                ("SRV", "0 5 25565 mc.example.com", true),
+                ("SRV", "0 5 mc.example.com", false),
+                ("HTTPS", ". alpn=h2 ipv4hint={ip} port={port}", true),
+                ("HTTPS", "www.example.com", true),
+                ("HTTPS", "", false),
+                ("HTTPS", ". alpn=h2 no-default-alpn", true),
+                ("HTTPS", "alpn=h2", false),
+                ("SVCB", ". =443", false),
+                ("SVCB", ". Port=443", false),
+                ("CAA", "0 issue letsencrypt.org", true),
+                ("CAA", "0 issue", false),
+                ("TXT", "{ip}:{port}", true),
+            ] {
+                let result = validate_value(record_type, &value.to_string());
+                assert_eq!(result.is_ok(), ok, "{record_type} `{value}`: {result:?}");
+            }
+        }
+    }
+}
+
+pub mod chat {
+    use super::{format_event, Event, PermanentError};
+    use anyhow::anyhow;
+    use reqwest::header::RETRY_AFTER;
+    use reqwest::{RequestBuilder, Response, StatusCode};
+    use serde::Deserialize;
+    use std::time::Duration;
+    use tracing::warn;
+
+    /// Maximum number of attempts when the server is rate limiting us.
+    const ATTEMPTS: usize = 3;
+
+    /// The longest time to wait for in total before giving up,
+    /// so the other watchers of the mapper are not held up.
+    const MAX_WAIT: Duration = Duration::from_secs(10);
+
+    /// Rate limit hints found in the response body of chat services.
+    #[derive(Deserialize, Default)]
+    struct RateLimit {
+        /// Telegram.
+        parameters: Option<Parameters>,
+        /// Matrix.
+        retry_after_ms: Option<u64>,
+        /// Discord.
+        retry_after: Option<f64>,
+    }
+
+    #[derive(Deserialize)]
+    struct Parameters {
+        retry_after: Option<u64>,
+    }
+
+    /// Returns the message to send.
+    /// The `value` field in watcher metadata takes precedence over the message template.
+    pub fn message(
+        template: &Option<String>,
+        event: &Event,
+        md: &super::config::Metadata,
+    ) -> String {
+        if !md.value.is_empty() {
+            return format_event(&md.value, event);
+        }
+        if let Some(template) = template {
+            return format_event(template, event);
+        }
+        let scheme = event.protocol;
+        match event.old_addr {
+            Some(old_addr) => format!(
+                "{}: {scheme}://{old_addr} --> {scheme}://{}",
+                event.mapper, event.addr
+            ),
+            None => format!("{}: {scheme}://{}", event.mapper, event.addr),
+        }
+    }
+
+    /// Send the request. If the server is rate limiting us, wait for
+    /// the time it suggests and try again.
+    /// If it takes too long, an error is returned and the mapper will retry later.
+    pub async fn send(name: &str, req: RequestBuilder) -> super::Result<Response> {
+        let mut attempt = 1;
+        let mut waited = Duration::ZERO;
+        loop {
+            // The url may contain credentials, e.g. the bot token.
+            let resp = req
+                .try_clone()
+                .ok_or(anyhow!("request is not cloneable"))?
+                .send()
+                .await
+                .map_err(|e| e.without_url())?;
+            if resp.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(resp);
+            }
+            let wait = retry_after(resp).await;
+            if attempt >= ATTEMPTS || waited + wait > MAX_WAIT {
+                return Err(anyhow!(
+                    "{}: retry after {}ms",
+                    StatusCode::TOO_MANY_REQUESTS,
+                    wait.as_millis()
+                ));
+            }
+            warn!(name, "rate limited, retry after {}ms", wait.as_millis());
+            tokio::time::sleep(wait).await;
+            waited += wait;
+            attempt += 1;
+        }
+    }
+
+    /// Returns the time to wait for before sending another request.
+    async fn retry_after(resp: Response) -> Duration {
+        let header = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        let millis = match header {
+            Some(sec) => sec * 1000,
+            None => {
+                let body: RateLimit = resp.json().await.unwrap_or_default();
+                body.parameters
+                    .and_then(|v| v.retry_after)
+                    .map(|v| v * 1000)
+                    .or(body.retry_after_ms)
+                    .or(body.retry_after.map(|v| (v * 1000.0) as u64))
+                    .unwrap_or(1000)
+            }
+        };
+        Duration::from_millis(millis)
+    }
+
+    /// Turn an unsuccessful response into an error.
+    /// Client errors, except for rate limiting, are not retried.
+    pub async fn check(resp: Response) -> super::Result<()> {
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = resp.text().await.unwrap_or_default();
+        let msg = format!("{status}: {}", body.trim());
+        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS {
+            Err(PermanentError::new(msg).into())
+        } else {
+            Err(anyhow!(msg))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::watcher::mock::{self, Response};
+
+        #[tokio::test]
+        async fn retry_rate_limited() {
+            let (url, mut requests) = mock::serve(vec![
+                Response::new(429, "").header("retry-after", "0"),
+                Response::new(429, r#"{"ok":false,"parameters":{"retry_after":0}}"#),
+                Response::new(200, "ok"),
+            ])
+            .await;
+            let req = reqwest::Client::new().post(url).body("hello");
+            let resp = send("test", req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            for _ in 0..3 {
+                assert_eq!(requests.recv().await.unwrap().body, "hello");
+            }
+        }
+
+        #[tokio::test]
+        async fn give_up_rate_limited() {
+            let (url, mut requests) = mock::serve(vec![
+                Response::new(429, r#"{"retry_after_ms":0}"#),
+                Response::new(429, r#"{"retry_after":0.0}"#),
+                Response::new(429, "").header("retry-after", "0"),
+            ])
+            .await;
+            let e = send("test", reqwest::Client::new().post(&url))
+                .await
+                .unwrap_err();
+            assert!(!e.is::<PermanentError>());
+            for _ in 0..ATTEMPTS {
+                assert_eq!(requests.recv().await.unwrap().method, "POST");
+            }
+
+            // Don't wait for too long, leave it to the mapper.
+            let (url, _) =
+                mock::serve(vec![Response::new(429, "").header("retry-after", "3600")]).await;
+            let e = send("test", reqwest::Client::new().post(url))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "429 Too Many Requests: retry after 3600000ms"
+            );
+        }
+    }
+}
+
+/// A local HTTP server for testing the watchers.
+#[cfg(test)]
+pub mod mock {
+    use super::config::Metadata;
+    use super::Watcher;
+    use anyhow::Result;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::path::PathBuf;
+    use stun::xoraddr::XorMappedAddress;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// A request received by the server.
+    pub struct Request {
+        pub method: String,
+        pub path: String,
+        headers: Vec<(String, String)>,
+        pub body: String,
+    }
+
+    impl Request {
+        /// Returns the value of the header.
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+
+        pub fn json(&self) -> serde_json::Value {
+            serde_json::from_str(&self.body).unwrap()
+        }
+    }
+
+    /// A response to send back.
+    pub struct Response {
+        status: u16,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Response {
+        pub fn new(status: u16, body: &str) -> Self {
+            Self {
+                status,
+                headers: Vec::new(),
+                body: body.to_string(),
+            }
+        }
+
+        pub fn header(mut self, name: &str, value: &str) -> Self {
+            self.headers.push((name.to_string(), value.to_string()));
+            self
+        }
+    }
+
+    /// Listen on a local port and answer the requests with the responses in order.
+    /// Returns the base url and the received requests.
+    pub async fn serve(responses: Vec<Response>) -> (String, mpsc::UnboundedReceiver<Request>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for resp in responses {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = BufReader::new(reader);
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap().to_string();
+                let path = parts.next().unwrap().to_string();
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    let Some((k, v)) = line.trim_end().split_once(':') else {
+                        break;
+                    };
+                    headers.push((k.to_string(), v.trim().to_string()));
+                }
+                let len = headers
+                    .iter()
+                    .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |(_, v)| v.parse().unwrap());
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).await.unwrap();
+                let _ = tx.send(Request {
+                    method,
+                    path,
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                });
+                let mut head = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n",
+                    resp.status,
+                    resp.body.len()
+                );
+                for (k, v) in resp.headers {
+                    head.push_str(&format!("{k}: {v}\r\n"));
+                }
+                head.push_str("\r\n");
+                writer.write_all(head.as_bytes()).await.unwrap();
+                writer.write_all(resp.body.as_bytes()).await.unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    /// The new address of the example event.
+    pub static ADDR: XorMappedAddress = XorMappedAddress {
+        ip: IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2)),
+        port: 2222,
+    };
+
+    /// Returns an example event of the mapper changing to `ADDR`.
+    pub fn event(old_addr: Option<SocketAddr>) -> super::Event<'static> {
+        super::Event {
+            mapper: "tcp://0.0.0.0:6666",
+            protocol: "tcp",
+            addr: &ADDR,
+            old_addr,
+            local_addr: "0.0.0.0:6666".parse().unwrap(),
+            upnp_port: None,
+        }
+    }
+
+    /// Notify the watcher of the first address of the example mapper.
+    pub async fn new_address(watcher: &(impl Watcher + ?Sized), md: &Metadata) -> Result<()> {
+        watcher.new_address(&event(None), md).await
+    }
+
+    /// Returns a path in the temp directory which is unique to the test.
+    /// Anything left at the path by a previous run is removed.
+    pub fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("nat2-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    /// Returns the metadata with the watcher name and value.
+    pub fn metadata(name: &str, value: &str) -> Metadata {
+        serde_json::from_value(serde_json::json!({"name": name, "value": value})).unwrap()
+    }
 }
@@ -0,0 +1,419 @@
+use crate::config::Metadata;
+use crate::watcher::{format_event, format_value, Event, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use rumqttc::{
+    AsyncClient, Event as MqttEvent, LastWill, MqttOptions, Outgoing, Packet, QoS,
+    TlsConfiguration, Transport,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, error};
+use url::ParseError::EmptyHost;
+use url::Url;
+
+/// Prefix of the default topic, which is followed by the mapper name.
+const TOPIC: &str = "nat2/";
+
+/// Default message template.
+const MESSAGE: &str = "{ip}:{port}";
+
+/// The amount of time (in seconds) to wait before reconnecting.
+const RETRY_INTERVAL: u64 = 10;
+
+/// The amount of time (in seconds) to wait for the connection to be established.
+const CONNECT_TIMEOUT: u64 = 10;
+
+/// The amount of time (in seconds) to wait for the broker to acknowledge a message.
+const ACK_TIMEOUT: u64 = 10;
+
+/// The amount of time (in seconds) to wait for the disconnection to be sent.
+const DISCONNECT_TIMEOUT: u64 = 2;
+
+/// Publish the mapped address to an MQTT broker.
+pub struct Mqtt {
+    /// Instance name.
+    name: String,
+    /// Connection options.
+    options: MqttOptions,
+    /// Topic template. Default is `nat2/` followed by the mapper name.
+    topic: Option<String>,
+    /// Quality of service.
+    qos: QoS,
+    /// Whether the message is retained by the broker.
+    retain: bool,
+    /// Topic of the `online` and last will `offline` message.
+    status_topic: Option<String>,
+    /// The connection is established when the watcher is started,
+    /// so the last will is registered with the broker right away.
+    conn: Mutex<Option<Connection>>,
+}
+
+struct Connection {
+    client: AsyncClient,
+    /// Whether the client is connected to the broker.
+    connected: watch::Receiver<bool>,
+    /// Messages waiting for the acknowledgement of the broker.
+    acks: Arc<Mutex<Acks>>,
+    /// Event loop of the client.
+    handle: JoinHandle<()>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Messages published with QoS 1 or 2, waiting for the acknowledgement of the broker.
+///
+/// The client does not tell the packet id of a message, so the messages are matched with the
+/// publish events of the event loop in the order they are queued.
+#[derive(Default)]
+struct Acks {
+    /// Messages queued in the client.
+    queued: VecDeque<oneshot::Sender<()>>,
+    /// Messages sent to the broker, keyed by packet id.
+    sent: HashMap<u16, oneshot::Sender<()>>,
+}
+
+impl Acks {
+    /// A message is sent, or held back until its packet id is released by the broker.
+    fn sent(&mut self, pkid: u16) {
+        // Packet id 0 is QoS 0, and a known id is a retransmission after reconnecting.
+        if pkid == 0 || self.sent.contains_key(&pkid) {
+            return;
+        }
+        if let Some(tx) = self.queued.pop_front() {
+            self.sent.insert(pkid, tx);
+        }
+    }
+
+    /// A message is acknowledged by the broker.
+    fn acked(&mut self, pkid: u16) {
+        if let Some(tx) = self.sent.remove(&pkid) {
+            let _ = tx.send(());
+        }
+    }
+}
+
+/// Queue a message in the client.
+/// Returns the receiver of the acknowledgement if the QoS is at least 1.
+fn publish(
+    client: &AsyncClient,
+    acks: &Mutex<Acks>,
+    topic: &str,
+    qos: QoS,
+    retain: bool,
+    payload: String,
+) -> Result<Option<oneshot::Receiver<()>>> {
+    // The lock is held until the message is tracked, so the event loop can't see it earlier.
+    let mut acks = acks.lock().unwrap();
+    client.try_publish(topic, qos, retain, payload)?;
+    if qos == QoS::AtMostOnce {
+        return Ok(None);
+    }
+    let (tx, rx) = oneshot::channel();
+    acks.queued.push_back(tx);
+    Ok(Some(rx))
+}
+
+/// Use the mapper name as a single topic level.
+/// Mapper names may be endpoint urls, e.g. `tcp://0.0.0.0:6666` becomes `tcp_0.0.0.0_6666`,
+/// since `/` separates levels and `+` and `#` are wildcards.
+fn topic_level(name: &str) -> String {
+    let mut level = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+            level.push(c);
+        } else if !level.ends_with('_') {
+            level.push('_');
+        }
+    }
+    level
+}
+
+impl Mqtt {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        url: String,
+        client_id: Option<String>,
+        credentials: Option<(String, String)>,
+        topic: Option<String>,
+        qos: Option<u8>,
+        retain: Option<bool>,
+        status_topic: Option<String>,
+    ) -> Result<Self> {
+        let url = Url::parse(url.as_str())?;
+        let host = url.host_str().ok_or(EmptyHost)?;
+        let client_id = client_id.unwrap_or(match hostname::get() {
+            Ok(name) => format!("nat2-{}", name.to_string_lossy()),
+            Err(_) => "nat2".to_string(),
+        });
+        let qos = match qos.unwrap_or(1) {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            v => return Err(anyhow!("unsupported qos `{v}`")),
+        };
+        let mut options = match url.scheme() {
+            "mqtt" | "tcp" => MqttOptions::new(client_id, host, url.port().unwrap_or(1883)),
+            "mqtts" | "ssl" => {
+                let mut options = MqttOptions::new(client_id, host, url.port().unwrap_or(8883));
+                options.set_transport(Transport::tls_with_config(TlsConfiguration::Native));
+                options
+            }
+            v => return Err(anyhow!("unsupported scheme `{v}`")),
+        };
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some((username, password)) = credentials {
+            options.set_credentials(username, password);
+        }
+        if let Some(topic) = &status_topic {
+            options.set_last_will(LastWill::new(topic, "offline", qos, true));
+        }
+        Ok(Self {
+            name,
+            options,
+            topic,
+            qos,
+            retain: retain.unwrap_or(true),
+            status_topic,
+            conn: Mutex::new(None),
+        })
+    }
+
+    /// Start the event loop of the client.
+    /// The client reconnects automatically when the connection is lost.
+    fn connect(&self) -> Connection {
+        let (client, mut eventloop) = AsyncClient::new(self.options.clone(), 10);
+        let (tx, rx) = watch::channel(false);
+        let acks = Arc::new(Mutex::new(Acks::default()));
+        let name = self.name.clone();
+        let status = self
+            .status_topic
+            .clone()
+            .map(|v| (client.clone(), v, self.qos));
+        let pending = acks.clone();
+        let handle = tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                        debug!(name, "connected to broker");
+                        let _ = tx.send(true);
+                        if let Some((client, topic, qos)) = &status {
+                            let online = "online".to_string();
+                            if let Err(e) = publish(client, &pending, topic, *qos, true, online) {
+                                error!(name, "{e}");
+                            }
+                        }
+                    }
+                    Ok(MqttEvent::Outgoing(Outgoing::Publish(pkid) | Outgoing::AwaitAck(pkid))) => {
+                        pending.lock().unwrap().sent(pkid);
+                    }
+                    Ok(MqttEvent::Incoming(Packet::PubAck(ack))) => {
+                        pending.lock().unwrap().acked(ack.pkid);
+                    }
+                    Ok(MqttEvent::Incoming(Packet::PubComp(comp))) => {
+                        pending.lock().unwrap().acked(comp.pkid);
+                    }
+                    Ok(MqttEvent::Outgoing(Outgoing::Disconnect)) => {
+                        debug!(name, "disconnected from broker");
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        let _ = tx.send(false);
+                        error!(name, "{e}");
+                        sleep(Duration::from_secs(RETRY_INTERVAL)).await;
+                    }
+                }
+            }
+        });
+        Connection {
+            client,
+            connected: rx,
+            acks,
+            handle,
+        }
+    }
+}
+
+#[async_trait]
+impl Watcher for Mqtt {
+    fn kind(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let (client, mut connected, acks) = match self.conn.lock().unwrap().as_ref() {
+            Some(conn) => (
+                conn.client.clone(),
+                conn.connected.clone(),
+                conn.acks.clone(),
+            ),
+            None => return Err(anyhow!("watcher is not started")),
+        };
+        timeout(
+            Duration::from_secs(CONNECT_TIMEOUT),
+            connected.wait_for(|v| *v),
+        )
+        .await
+        .map_err(|_| anyhow!("not connected to broker"))??;
+        let topic = match &self.topic {
+            Some(topic) => format_event(topic, event),
+            None => format!("{TOPIC}{}", topic_level(event.mapper)),
+        };
+        let payload = if md.value.is_empty() {
+            format_value(&MESSAGE.to_string(), event.addr)
+        } else {
+            format_event(&md.value, event)
+        };
+        debug!(topic, name = self.name(), "publish {payload}");
+        let ack = publish(&client, &acks, &topic, self.qos, self.retain, payload)?;
+        // Only the connection keeps the message, so it fails right away once stopped.
+        drop(acks);
+        // The message is only queued in the client until the broker acknowledges it.
+        if let Some(ack) = ack {
+            timeout(Duration::from_secs(ACK_TIMEOUT), ack)
+                .await
+                .map_err(|_| anyhow!("broker did not acknowledge the message"))?
+                .map_err(|_| anyhow!("disconnected from broker"))?;
+        }
+        Ok(())
+    }
+
+    async fn validate(&self, _: &Metadata) -> Result<()> {
+        Ok(())
+    }
+
+    async fn start(&self) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.is_none() {
+            *conn = Some(self.connect());
+        }
+        Ok(())
+    }
+
+    async fn stop(&self) {
+        let Some(mut conn) = self.conn.lock().unwrap().take() else {
+            return;
+        };
+        // The last will is not published on a clean disconnection.
+        if let Some(topic) = &self.status_topic {
+            let offline = "offline".to_string();
+            let _ = publish(&conn.client, &conn.acks, topic, self.qos, true, offline);
+        }
+        if conn.client.try_disconnect().is_ok() {
+            let _ = timeout(Duration::from_secs(DISCONNECT_TIMEOUT), &mut conn.handle).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    /// Read an MQTT packet, and return its first byte and the rest after the length.
+    async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let header = stream.read_u8().await.unwrap();
+        let (mut len, mut shift) = (0, 0);
+        loop {
+            let byte = stream.read_u8().await.unwrap();
+            len |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body).await.unwrap();
+        (header, body)
+    }
+
+    #[test]
+    fn default_topic() {
+        assert_eq!(topic_level("tcp://0.0.0.0:6666"), "tcp_0.0.0.0_6666");
+        assert_eq!(topic_level("web-1"), "web-1");
+        assert_eq!(topic_level("a/+/#"), "a_");
+    }
+
+    #[test]
+    fn acks_matched_in_order() {
+        let mut acks = Acks::default();
+        let (first, mut first_rx) = oneshot::channel();
+        let (second, mut second_rx) = oneshot::channel();
+        acks.queued.extend([first, second]);
+        acks.sent(1);
+        // Retransmitted after reconnecting.
+        acks.sent(1);
+        acks.sent(2);
+        acks.acked(2);
+        assert!(second_rx.try_recv().is_ok());
+        assert!(first_rx.try_recv().is_err());
+        acks.acked(1);
+        assert!(first_rx.try_recv().is_ok());
+        assert!(acks.queued.is_empty() && acks.sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wait_for_puback() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("mqtt://{}", listener.local_addr().unwrap());
+        let (tx, mut published) = mpsc::unbounded_channel();
+        let (ack, acked) = oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (header, _) = read_packet(&mut stream).await;
+            assert_eq!(header >> 4, 1);
+            stream.write_all(&[0x20, 2, 0, 0]).await.unwrap();
+            let (header, body) = read_packet(&mut stream).await;
+            assert_eq!(header >> 4, 3);
+            let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+            let pkid = [body[2 + len], body[3 + len]];
+            let payload = String::from_utf8(body[4 + len..].to_vec()).unwrap();
+            tx.send((topic, payload)).unwrap();
+            acked.await.unwrap();
+            stream
+                .write_all(&[0x40, 2, pkid[0], pkid[1]])
+                .await
+                .unwrap();
+            // Keep the connection open until the client disconnects.
+            let _ = stream.read_u8().await;
+        });
+
+        let mqtt = Mqtt::new("m".to_string(), url, None, None, None, None, None, None).unwrap();
+        mqtt.start().await.unwrap();
+        let event = mock::event(None);
+        let md = mock::metadata("m", "");
+        let publish = mqtt.new_address(&event, &md);
+        tokio::pin!(publish);
+        let (topic, payload) = tokio::select! {
+            v = published.recv() => v.unwrap(),
+            v = &mut publish => panic!("finished before the broker received it: {v:?}"),
+        };
+        assert_eq!(topic, "nat2/tcp_0.0.0.0_6666");
+        assert_eq!(payload, "2.2.2.2:2222");
+        // Not acknowledged yet.
+        assert!(timeout(Duration::from_millis(200), &mut publish)
+            .await
+            .is_err());
+        ack.send(()).unwrap();
+        publish.await.unwrap();
+        mqtt.stop().await;
+    }
+}
@@ -0,0 +1,363 @@
+use crate::config::Metadata;
+use crate::watcher::{Event, EventData, PermanentError, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Default time in seconds to wait for a response.
+const TIMEOUT: u64 = 30;
+
+/// A long-running program which talks JSON lines over stdio.
+///
+/// Each request is written to the standard input of the program as a single line,
+/// e.g. `{"id":1,"method":"new_address","params":{"event":{...},"metadata":{...}}}`,
+/// and the program answers with a line carrying the same id, e.g. `{"id":1}` or
+/// `{"id":1,"error":"bad credentials","permanent":true}`.
+pub struct Plugin {
+    /// Instance name.
+    name: String,
+    /// Path to executable file.
+    path: String,
+    /// Arguments to pass to the program.
+    args: Vec<String>,
+    /// Working directory of the program.
+    dir: Option<String>,
+    /// Extra environment variables.
+    env: HashMap<String, String>,
+    /// The amount of time to wait for a response.
+    timeout: Duration,
+    /// Whether the watcher is started. The program is not run before that,
+    /// e.g. when the configuration is only checked.
+    started: AtomicBool,
+    /// Running program, started on the first request.
+    process: Mutex<Option<Process>>,
+    /// Id of the last request.
+    id: AtomicU64,
+}
+
+/// Pipes of the running program.
+struct Process {
+    /// The program is killed when it is dropped.
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    id: u64,
+    method: &'a str,
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    id: u64,
+    error: Option<String>,
+    #[serde(default)]
+    permanent: bool,
+}
+
+impl Process {
+    /// Write the request and wait for the response with the same id.
+    async fn call(&mut self, req: &Request<'_>) -> Result<Response> {
+        let mut line = serde_json::to_vec(req)?;
+        line.push(b'\n');
+        self.stdin.write_all(&line).await?;
+        self.stdin.flush().await?;
+        loop {
+            let line = self
+                .stdout
+                .next_line()
+                .await?
+                .ok_or(anyhow!("process exited unexpectedly"))?;
+            match serde_json::from_str::<Response>(&line) {
+                Ok(resp) if resp.id == req.id => return Ok(resp),
+                // Stale response of a timed out request, or plain output.
+                _ => debug!("skip line `{line}`"),
+            }
+        }
+    }
+}
+
+impl Plugin {
+    pub fn new(
+        name: String,
+        path: String,
+        args: Vec<String>,
+        dir: Option<String>,
+        env: HashMap<String, String>,
+        timeout: Option<u64>,
+    ) -> Self {
+        Self {
+            name,
+            path,
+            args,
+            dir,
+            env,
+            timeout: Duration::from_secs(timeout.filter(|v| *v > 0).unwrap_or(TIMEOUT)),
+            started: AtomicBool::new(false),
+            process: Mutex::new(None),
+            id: AtomicU64::new(0),
+        }
+    }
+
+    /// Start the program and log its standard error in the background.
+    fn spawn(&self) -> Result<Process> {
+        let mut command = Command::new(&self.path);
+        command
+            .args(&self.args)
+            .envs(&self.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(dir) = &self.dir {
+            command.current_dir(dir);
+        }
+        debug!(name = self.name(), "starting new {:?}", command);
+        let mut child = command.spawn()?;
+        let stdin = child.stdin.take().ok_or(anyhow!("stdin is not piped"))?;
+        let stdout = child.stdout.take().ok_or(anyhow!("stdout is not piped"))?;
+        if let Some(stderr) = child.stderr.take() {
+            let name = self.name.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    warn!(name, "{line}");
+                }
+            });
+        }
+        Ok(Process {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+        })
+    }
+
+    /// Send a request to the program, starting it if necessary.
+    /// The program is restarted on the next request if it crashes or does not respond in time.
+    async fn request(&self, method: &str, params: Value) -> Result<()> {
+        let mut process = self.process.lock().await;
+        if process.is_none() {
+            if !self.started.load(Ordering::Relaxed) {
+                return Err(anyhow!("watcher is not started"));
+            }
+            *process = Some(self.spawn()?);
+        }
+        let req = Request {
+            id: self.id.fetch_add(1, Ordering::Relaxed) + 1,
+            method,
+            params,
+        };
+        let result = tokio::time::timeout(self.timeout, process.as_mut().unwrap().call(&req))
+            .await
+            .map_err(|_| anyhow!("no response after {}s", self.timeout.as_secs()))
+            .and_then(|v| v);
+        let resp = match result {
+            Ok(resp) => resp,
+            Err(e) => {
+                *process = None;
+                return Err(e);
+            }
+        };
+        match resp.error {
+            None => Ok(()),
+            Some(e) if resp.permanent => Err(PermanentError::new(e).into()),
+            Some(e) => Err(anyhow!(e)),
+        }
+    }
+}
+
+#[async_trait]
+impl Watcher for Plugin {
+    fn kind(&self) -> &'static str {
+        "plugin"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let params = json!({"event": EventData::from(event), "metadata": md});
+        self.request("new_address", params).await
+    }
+
+    async fn validate(&self, md: &Metadata) -> Result<()> {
+        // The metadata is validated by the program when it is started.
+        if !self.started.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let params = json!({"metadata": md});
+        self.request("validate", params).await
+    }
+
+    fn validates_when_started(&self) -> bool {
+        true
+    }
+
+    async fn closed(&self, mapper: &str, md: &Metadata) -> Result<()> {
+        // Don't start the program just to tell it about the closed mapper.
+        if self.process.lock().await.is_none() {
+            return Ok(());
+        }
+        let params = json!({"mapper": mapper, "metadata": md});
+        self.request("closed", params).await
+    }
+
+    async fn start(&self) -> Result<()> {
+        self.started.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn stop(&self) {
+        self.started.store(false, Ordering::Relaxed);
+        // The program is killed when it is dropped.
+        self.process.lock().await.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock;
+
+    /// Shell snippet which sets `id` to the id of the request in `l`.
+    const ID: &str = r#"id=$(echo "$l" | sed 's/^{"id":\([0-9]*\).*/\1/')"#;
+
+    /// Returns a plugin which answers each request with the given shell snippet.
+    fn plugin(reply: &str, timeout: Option<u64>) -> Plugin {
+        Plugin::new(
+            "p".to_string(),
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                format!("while read l; do {ID}; {reply}; done"),
+            ],
+            None,
+            HashMap::new(),
+            timeout,
+        )
+    }
+
+    async fn run(plugin: &Plugin) -> Result<()> {
+        mock::new_address(plugin, &mock::metadata("p", "")).await
+    }
+
+    #[tokio::test]
+    async fn success() {
+        let plugin = plugin(r#"echo "{\"id\":$id}""#, None);
+        plugin.start().await.unwrap();
+        run(&plugin).await.unwrap();
+        run(&plugin).await.unwrap();
+        plugin.validate(&mock::metadata("p", "")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn error() {
+        let plugin = plugin(
+            r#"echo "{\"id\":$id,\"error\":\"bad credentials\",\"permanent\":true}""#,
+            None,
+        );
+        plugin.start().await.unwrap();
+        let e = run(&plugin).await.unwrap_err();
+        assert!(e.is::<PermanentError>());
+        assert_eq!(e.to_string(), "bad credentials");
+
+        let plugin = self::plugin(r#"echo "{\"id\":$id,\"error\":\"busy\"}""#, None);
+        plugin.start().await.unwrap();
+        let e = run(&plugin).await.unwrap_err();
+        assert!(!e.is::<PermanentError>());
+        assert_eq!(e.to_string(), "busy");
+    }
+
+    #[tokio::test]
+    async fn mismatched_id_skipped() {
+        let plugin = plugin(
+            r#"echo "{\"id\":0,\"error\":\"stale\"}"; echo plain; echo "{\"id\":$id}""#,
+            None,
+        );
+        plugin.start().await.unwrap();
+        run(&plugin).await.unwrap();
+        // Only a mismatched id is never a response.
+        let plugin = self::plugin(r#"echo "{\"id\":0}""#, Some(1));
+        plugin.start().await.unwrap();
+        let e = run(&plugin).await.unwrap_err();
+        assert_eq!(e.to_string(), "no response after 1s");
+    }
+
+    #[tokio::test]
+    async fn restarted_after_timeout() {
+        let path = mock::temp_path("plugin-timeout");
+        // The first program hangs, the restarted one answers.
+        let reply = format!(
+            r#"if [ -e {0} ]; then echo "{{\"id\":$id}}"; else touch {0}; sleep 5; fi"#,
+            path.display()
+        );
+        let plugin = plugin(&reply, Some(1));
+        plugin.start().await.unwrap();
+        let e = run(&plugin).await.unwrap_err();
+        assert_eq!(e.to_string(), "no response after 1s");
+        run(&plugin).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn restarted_after_exit() {
+        let path = mock::temp_path("plugin-exit");
+        // Each program answers one request, then exits.
+        let plugin = Plugin::new(
+            "p".to_string(),
+            "sh".to_string(),
+            vec![
+                "-c".to_string(),
+                format!(
+                    r#"echo >> {}; read l; {ID}; echo "{{\"id\":$id}}""#,
+                    path.display()
+                ),
+            ],
+            None,
+            HashMap::new(),
+            None,
+        );
+        plugin.start().await.unwrap();
+        run(&plugin).await.unwrap();
+        assert!(run(&plugin).await.is_err());
+        run(&plugin).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn not_started() {
+        let path = mock::temp_path("plugin-started");
+        let plugin = plugin(
+            &format!(r#"echo >> {}; echo "{{\"id\":$id}}""#, path.display()),
+            None,
+        );
+        let e = run(&plugin).await.unwrap_err();
+        assert_eq!(e.to_string(), "watcher is not started");
+        // Checking the config doesn't run the program.
+        plugin.validate(&mock::metadata("p", "")).await.unwrap();
+        assert!(!path.exists());
+
+        plugin.start().await.unwrap();
+        run(&plugin).await.unwrap();
+        assert!(path.exists());
+        plugin.stop().await;
+        assert!(plugin.process.lock().await.is_none());
+        let e = run(&plugin).await.unwrap_err();
+        assert_eq!(e.to_string(), "watcher is not started");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::config::Metadata;
+use crate::watcher::{Event, PermanentError, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::header::REFERER;
+use reqwest::{Client, Response, StatusCode};
+use serde_json::json;
+use tracing::debug;
+use url::Url;
+
+/// [qBittorrent](https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)) WebUI.
+pub struct QBittorrent {
+    /// Instance name.
+    name: String,
+    /// WebUI url.
+    url: Url,
+    /// Username and password of WebUI.
+    credentials: Option<(String, String)>,
+    /// Whether to set the announce ip to the mapped ip.
+    announce_ip: bool,
+    /// The session cookie is kept in the cookie store of the client,
+    /// whatever its name is (`SID`, or `QBT_SID_<port>` in newer versions).
+    client: Client,
+}
+
+impl QBittorrent {
+    pub fn new(
+        name: String,
+        url: String,
+        credentials: Option<(String, String)>,
+        announce_ip: bool,
+    ) -> Result<Self> {
+        let mut url = Url::parse(url.as_str())?;
+        // API paths are joined to the url.
+        if !url.path().ends_with('/') {
+            url.set_path(format!("{}/", url.path()).as_str());
+        }
+        Ok(Self {
+            name,
+            url,
+            credentials,
+            announce_ip,
+            client: Client::builder().cookie_store(true).build()?,
+        })
+    }
+
+    /// Log in and save the session cookie.
+    /// See <https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)#login>.
+    async fn login(&self) -> Result<()> {
+        let (username, password) = self.credentials.as_ref().ok_or(PermanentError::new(
+            "forbidden, missing username and password",
+        ))?;
+        let body = self
+            .client
+            .post(self.url.join("api/v2/auth/login")?)
+            .header(REFERER, self.url.as_str())
+            .form(&[("username", username), ("password", password)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        // `Fails.` is returned with 200 for wrong credentials.
+        if body.trim() != "Ok." {
+            return Err(PermanentError::new(format!("login failed: {}", body.trim())).into());
+        }
+        debug!(name = self.name(), "login succeed");
+        Ok(())
+    }
+
+    /// Set application preferences.
+    /// See <https://github.com/qbittorrent/qBittorrent/wiki/WebUI-API-(qBittorrent-4.1)#set-application-preferences>.
+    async fn set_preferences(&self, preferences: &str) -> Result<Response> {
+        Ok(self
+            .client
+            .post(self.url.join("api/v2/app/setPreferences")?)
+            .header(REFERER, self.url.as_str())
+            .form(&[("json", preferences)])
+            .send()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl Watcher for QBittorrent {
+    fn kind(&self) -> &'static str {
+        "qbittorrent"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, _: &Metadata) -> Result<()> {
+        let mut preferences = json!({ "listen_port": event.addr.port });
+        if self.announce_ip {
+            preferences["announce_ip"] = json!(event.addr.ip.to_string());
+        }
+        let preferences = preferences.to_string();
+        let mut resp = self.set_preferences(preferences.as_str()).await?;
+        if resp.status() == StatusCode::FORBIDDEN {
+            // Session expired or not logged in yet.
+            self.login().await?;
+            resp = self.set_preferences(preferences.as_str()).await?;
+        }
+        let status = resp.status();
+        if !status.is_success() {
+            return Err(anyhow!("{status}: {}", resp.text().await?.trim()));
+        }
+        debug!(name = self.name(), "set preferences {preferences}");
+        Ok(())
+    }
+
+    async fn validate(&self, _: &Metadata) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+
+    #[tokio::test]
+    async fn login_cookie() {
+        let (url, mut requests) = mock::serve(vec![
+            Response::new(403, "Forbidden"),
+            Response::new(200, "Ok.").header(
+                "set-cookie",
+                "QBT_SID_8080=abc; HttpOnly; SameSite=Strict; path=/",
+            ),
+            Response::new(200, ""),
+            Response::new(200, ""),
+        ])
+        .await;
+        let qbittorrent = QBittorrent::new(
+            "q".to_string(),
+            format!("{url}/qb"),
+            Some(("admin".to_string(), "secret".to_string())),
+            true,
+        )
+        .unwrap();
+        let md = mock::metadata("q", "");
+        mock::new_address(&qbittorrent, &md).await.unwrap();
+
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.path, "/qb/api/v2/app/setPreferences");
+        assert_eq!(req.header("cookie"), None);
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.path, "/qb/api/v2/auth/login");
+        assert_eq!(req.body, "username=admin&password=secret");
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.path, "/qb/api/v2/app/setPreferences");
+        assert_eq!(req.header("cookie"), Some("QBT_SID_8080=abc"));
+        assert_eq!(
+            req.body,
+            "json=%7B%22announce_ip%22%3A%222.2.2.2%22%2C%22listen_port%22%3A2222%7D"
+        );
+
+        // The session is reused.
+        mock::new_address(&qbittorrent, &md).await.unwrap();
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.header("cookie"), Some("QBT_SID_8080=abc"));
+    }
+
+    #[tokio::test]
+    async fn login_failed() {
+        let (url, _requests) = mock::serve(vec![
+            Response::new(403, "Forbidden"),
+            Response::new(200, "Fails."),
+        ])
+        .await;
+        let qbittorrent = QBittorrent::new(
+            "q".to_string(),
+            url,
+            Some(("admin".to_string(), "wrong".to_string())),
+            false,
+        )
+        .unwrap();
+        let e = mock::new_address(&qbittorrent, &mock::metadata("q", ""))
+            .await
+            .unwrap_err();
+        assert!(e.is::<PermanentError>(), "{e}");
+        assert_eq!(e.to_string(), "login failed: Fails.");
+    }
+}
@@ -1,10 +1,13 @@
 use crate::config::Metadata;
-use crate::watcher::{format_value, Watcher};
+use crate::watcher::{format_value, Event, EventData, PermanentError, Watcher};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use stun::xoraddr::XorMappedAddress;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
 use tokio::process::Command;
-use tracing::debug;
+use tracing::{debug, info, warn};
 
 /// Run a script or program.
 pub struct Script {
@@ -16,14 +19,62 @@ pub struct Script {
     /// If the `value` field in watcher metadata is not empty, it
     /// will be passed to the program as the last argument.
     args: Vec<String>,
+    /// Working directory of the program.
+    dir: Option<String>,
+    /// Extra environment variables.
+    env: HashMap<String, String>,
+    /// The amount of time to wait before killing the program.
+    timeout: Option<Duration>,
+    /// Exit codes which indicate a temporary failure.
+    /// If not empty, other non-zero exit codes are permanent failures.
+    retry_codes: Vec<i32>,
 }
 
 impl Script {
-    pub fn new(name: String, path: String, args: Vec<String>) -> Self {
-        Self { name, path, args }
+    pub fn new(
+        name: String,
+        path: String,
+        args: Vec<String>,
+        dir: Option<String>,
+        env: HashMap<String, String>,
+        timeout: Option<u64>,
+        retry_codes: Vec<i32>,
+    ) -> Self {
+        Self {
+            name,
+            path,
+            args,
+            dir,
+            env,
+            timeout: timeout.filter(|v| *v > 0).map(Duration::from_secs),
+            retry_codes,
+        }
     }
 }
 
+/// Log the output of the program line by line.
+/// Returns all the lines that have been read.
+async fn log_lines<R: AsyncRead + Unpin>(
+    name: &str,
+    reader: Option<R>,
+    stderr: bool,
+) -> Vec<String> {
+    let mut output = Vec::new();
+    let Some(reader) = reader else {
+        return output;
+    };
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if stderr {
+            warn!(name, "{line}");
+        } else {
+            info!(name, "{line}");
+        }
+        output.push(line);
+    }
+    output
+}
+
 #[async_trait]
 impl Watcher for Script {
     fn kind(&self) -> &'static str {
@@ -34,28 +85,163 @@ impl Watcher for Script {
         self.name.as_str()
     }
 
-    async fn new_address(&self, addr: &XorMappedAddress, md: &Metadata) -> Result<()> {
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
         let mut command = Command::new(&self.path);
         command.args(&self.args);
         if !md.value.is_empty() {
-            command.arg(format_value(&md.value, addr));
+            command.arg(format_value(&md.value, event.addr));
+        }
+        if let Some(dir) = &self.dir {
+            command.current_dir(dir);
         }
+        // Event data is also written to the standard input of the program.
+        let input = EventData::from(event);
+        command
+            .env("NAT2_MAPPER", input.mapper)
+            .env("NAT2_PROTOCOL", input.protocol)
+            .env("NAT2_IP", &input.ip)
+            .env("NAT2_PORT", input.port.to_string())
+            .env("NAT2_OLD_IP", input.old_ip.as_deref().unwrap_or_default())
+            .env(
+                "NAT2_OLD_PORT",
+                input.old_port.map(|v| v.to_string()).unwrap_or_default(),
+            )
+            .env("NAT2_LOCAL_ADDR", &input.local_addr)
+            .env(
+                "NAT2_UPNP_PORT",
+                input.upnp_port.map(|v| v.to_string()).unwrap_or_default(),
+            )
+            .envs(&self.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
         debug!(name = self.name(), "starting new {:?}", command);
-        let output = command.output().await?;
-        debug!(
-            name = self.name(),
-            "process finished with {}", output.status
-        );
-        if output.status.success() {
-            Ok(())
-        } else if !output.stderr.is_empty() {
-            Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr)))
+        let mut child = command.spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // The program may exit without reading the input.
+            let _ = stdin.write_all(&serde_json::to_vec(&input)?).await;
+        }
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let run = async {
+            let (status, _, stderr) = tokio::join!(
+                child.wait(),
+                log_lines(self.name(), stdout, false),
+                log_lines(self.name(), stderr, true)
+            );
+            status.map(|v| (v, stderr))
+        };
+        let (status, stderr) = if let Some(timeout) = self.timeout {
+            // The child is killed when it is dropped.
+            tokio::time::timeout(timeout, run)
+                .await
+                .map_err(|_| anyhow!("process timed out after {}s", timeout.as_secs()))??
+        } else {
+            run.await?
+        };
+        debug!(name = self.name(), "process finished with {}", status);
+        if status.success() {
+            return Ok(());
+        }
+        let err = if !stderr.is_empty() {
+            stderr.join("\n")
         } else {
-            Err(anyhow!("process finished with {}", output.status))
+            format!("process finished with {}", status)
+        };
+        match status.code() {
+            Some(code) if !self.retry_codes.is_empty() && !self.retry_codes.contains(&code) => {
+                Err(PermanentError::new(err).into())
+            }
+            _ => Err(anyhow!(err)),
         }
     }
 
-    fn validate(&self, _: &Metadata) -> Result<()> {
+    async fn validate(&self, _: &Metadata) -> Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock;
+
+    fn script(command: &str, timeout: Option<u64>, retry_codes: Vec<i32>) -> Script {
+        Script::new(
+            "s".to_string(),
+            "sh".to_string(),
+            vec!["-c".to_string(), command.to_string()],
+            None,
+            HashMap::from([("EXTRA".to_string(), "x".to_string())]),
+            timeout,
+            retry_codes,
+        )
+    }
+
+    async fn run(script: &Script) -> Result<()> {
+        let event = mock::event(Some("1.1.1.1:1111".parse().unwrap()));
+        script.new_address(&event, &mock::metadata("s", "")).await
+    }
+
+    #[tokio::test]
+    async fn env_and_stdin() {
+        let path = mock::temp_path("script-env");
+        let command = format!(
+            "echo \"$NAT2_MAPPER $NAT2_PROTOCOL $NAT2_IP $NAT2_PORT $NAT2_OLD_IP $NAT2_OLD_PORT \
+             $NAT2_LOCAL_ADDR [$NAT2_UPNP_PORT] $EXTRA\" > {0}; cat >> {0}",
+            path.display()
+        );
+        run(&script(&command, None, vec![])).await.unwrap();
+        let output = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let (env, stdin) = output.split_once('\n').unwrap();
+        assert_eq!(
+            env,
+            "tcp://0.0.0.0:6666 tcp 2.2.2.2 2222 1.1.1.1 1111 0.0.0.0:6666 [] x"
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(stdin).unwrap(),
+            serde_json::json!({
+                "mapper": "tcp://0.0.0.0:6666",
+                "protocol": "tcp",
+                "ip": "2.2.2.2",
+                "port": 2222,
+                "old_ip": "1.1.1.1",
+                "old_port": 1111,
+                "local_addr": "0.0.0.0:6666",
+                "upnp_port": null,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn killed_after_timeout() {
+        let path = mock::temp_path("script-timeout");
+        let command = format!("sleep 2; echo late > {}", path.display());
+        let e = run(&script(&command, Some(1), vec![])).await.unwrap_err();
+        assert_eq!(e.to_string(), "process timed out after 1s");
+        // The program would have written the file by now if it was still running.
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn listed_code_retried() {
+        let e = run(&script("echo busy >&2; exit 75", None, vec![75]))
+            .await
+            .unwrap_err();
+        assert!(!e.is::<PermanentError>());
+        assert_eq!(e.to_string(), "busy");
+    }
+
+    #[tokio::test]
+    async fn unlisted_code_permanent() {
+        let e = run(&script("exit 1", None, vec![75])).await.unwrap_err();
+        assert!(e.is::<PermanentError>());
+        assert_eq!(e.to_string(), "process finished with exit status: 1");
+        // Without retry codes, every failure is retried.
+        let e = run(&script("exit 1", None, vec![])).await.unwrap_err();
+        assert!(!e.is::<PermanentError>());
+    }
+}
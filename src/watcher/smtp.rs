@@ -0,0 +1,178 @@
+use crate::config::Metadata;
+use crate::watcher::{chat, format_event, Event, PermanentError, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tracing::debug;
+
+/// Default subject template.
+const SUBJECT: &str = "{mapper} is mapped to {ip}:{port}";
+
+/// Send an email via SMTP server.
+pub struct Smtp {
+    /// Instance name.
+    name: String,
+    /// SMTP client.
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    /// Sender address.
+    from: Mailbox,
+    /// Recipient addresses.
+    to: Vec<Mailbox>,
+    /// Subject template.
+    subject: Option<String>,
+    /// Body template.
+    body: Option<String>,
+}
+
+impl Smtp {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        host: String,
+        port: Option<u16>,
+        tls: Option<String>,
+        credentials: Option<(String, String)>,
+        from: String,
+        to: Vec<String>,
+        subject: Option<String>,
+        body: Option<String>,
+    ) -> Result<Self> {
+        let mut builder = match tls.as_deref().unwrap_or("starttls") {
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host.as_str())?,
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(host.as_str())?,
+            "none" => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+            v => return Err(anyhow!("unsupported tls mode `{v}`")),
+        };
+        if let Some(port) = port {
+            builder = builder.port(port);
+        }
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        if to.is_empty() {
+            return Err(anyhow!("missing recipient address"));
+        }
+        Ok(Self {
+            name,
+            transport: builder.build(),
+            from: from.parse()?,
+            to: to
+                .iter()
+                .map(|v| v.parse())
+                .collect::<Result<Vec<Mailbox>, _>>()?,
+            subject,
+            body,
+        })
+    }
+
+    /// Build the email of an address change.
+    fn message(&self, event: &Event<'_>, md: &Metadata) -> Result<Message> {
+        let subject = format_event(self.subject.as_ref().unwrap_or(&SUBJECT.to_string()), event);
+        let mut message = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            message = message.to(to.clone());
+        }
+        Ok(message.body(chat::message(&self.body, event, md))?)
+    }
+}
+
+#[async_trait]
+impl Watcher for Smtp {
+    fn kind(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let message = self.message(event, md)?;
+        let resp = self.transport.send(message).await.map_err(|e| {
+            if e.is_permanent() {
+                PermanentError::new(e.to_string()).into()
+            } else {
+                anyhow::Error::from(e)
+            }
+        })?;
+        debug!(
+            code = resp.code().to_string(),
+            name = self.name(),
+            "email sent"
+        );
+        Ok(())
+    }
+
+    async fn validate(&self, _: &Metadata) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock;
+
+    fn smtp(tls: &str, from: &str, to: &[&str]) -> Result<Smtp> {
+        Smtp::new(
+            "smtp".to_string(),
+            "smtp.example.com".to_string(),
+            None,
+            Some(tls.to_string()),
+            None,
+            from.to_string(),
+            to.iter().map(|v| v.to_string()).collect(),
+            Some("{ip}:{port}".to_string()),
+            Some("{mapper} --> {ip}:{port}".to_string()),
+        )
+    }
+
+    #[test]
+    fn message() {
+        let smtp = smtp(
+            "starttls",
+            "nat2 <nat2@example.com>",
+            &["a@example.com", "b@example.com"],
+        )
+        .unwrap();
+        let message = smtp
+            .message(&mock::event(None), &mock::metadata("smtp", ""))
+            .unwrap();
+        let to: Vec<String> = message
+            .envelope()
+            .to()
+            .iter()
+            .map(|v| v.to_string())
+            .collect();
+        assert_eq!(to, ["a@example.com", "b@example.com"]);
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(
+            formatted.contains("Subject: 2.2.2.2:2222\r\n"),
+            "{formatted}"
+        );
+        assert!(
+            formatted.contains("To: a@example.com, b@example.com\r\n"),
+            "{formatted}"
+        );
+        assert!(
+            formatted.ends_with("\r\n\r\ntcp://0.0.0.0:6666 --> 2.2.2.2:2222"),
+            "{formatted}"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(smtp("starttls", "nat2@example.com", &["a@example.com"]).is_ok());
+        assert!(smtp("starttls", "nat2", &["a@example.com"]).is_err());
+        assert!(smtp("starttls", "nat2@example.com", &["a@example.com", "b"]).is_err());
+        assert!(smtp("starttls", "nat2@example.com", &[]).is_err());
+        let err = smtp("ssl", "nat2@example.com", &["a@example.com"]).err();
+        assert_eq!(err.unwrap().to_string(), "unsupported tls mode `ssl`");
+    }
+}
@@ -0,0 +1,135 @@
+use crate::config::Metadata;
+use crate::watcher::{chat, Event, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+use tracing::debug;
+use url::Url;
+
+/// Default Bot API server.
+const URL: &str = "https://api.telegram.org";
+
+/// [Telegram](https://core.telegram.org/bots/api) bot.
+pub struct Telegram {
+    /// Instance name.
+    name: String,
+    /// Request url of the `sendMessage` method.
+    url: Url,
+    /// Unique identifier for the target chat or username of the target channel.
+    chat_id: String,
+    /// Message template.
+    message: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SendMessageRequest<'a> {
+    chat_id: &'a str,
+    text: String,
+    disable_web_page_preview: bool,
+}
+
+impl Telegram {
+    pub fn new(
+        name: String,
+        token: String,
+        chat_id: String,
+        url: Option<String>,
+        message: Option<String>,
+    ) -> Result<Self> {
+        let mut url = Url::parse(url.as_deref().unwrap_or(URL))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid bot api url"))?
+            .pop_if_empty()
+            .extend([format!("bot{token}").as_str(), "sendMessage"]);
+        Ok(Self {
+            name,
+            url,
+            chat_id,
+            message,
+        })
+    }
+}
+
+#[async_trait]
+impl Watcher for Telegram {
+    fn kind(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let client = reqwest::Client::new();
+        let payload = SendMessageRequest {
+            chat_id: self.chat_id.as_str(),
+            text: chat::message(&self.message, event, md),
+            disable_web_page_preview: true,
+        };
+        let req = client.post(self.url.clone()).json(&payload);
+        let resp = chat::send(self.name(), req).await?;
+        debug!(
+            code = resp.status().as_str(),
+            name = self.name(),
+            "message sent"
+        );
+        chat::check(resp).await
+    }
+
+    async fn validate(&self, _: &Metadata) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+
+    #[tokio::test]
+    async fn send_message() {
+        let (url, mut requests) = mock::serve(vec![Response::new(200, r#"{"ok":true}"#)]).await;
+        let telegram = Telegram::new(
+            "t".to_string(),
+            "123:abc".to_string(),
+            "-100".to_string(),
+            Some(url),
+            None,
+        )
+        .unwrap();
+        let event = mock::event(Some("1.1.1.1:1111".parse().unwrap()));
+        telegram
+            .new_address(&event, &mock::metadata("t", ""))
+            .await
+            .unwrap();
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/bot123:abc/sendMessage");
+        assert_eq!(
+            req.json(),
+            serde_json::json!({
+                "chat_id": "-100",
+                "text": "tcp://0.0.0.0:6666: tcp://1.1.1.1:1111 --> tcp://2.2.2.2:2222",
+                "disable_web_page_preview": true,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn error_hides_token() {
+        // Nothing is listening on the port.
+        let telegram = Telegram::new(
+            "t".to_string(),
+            "123:abc".to_string(),
+            "-100".to_string(),
+            Some("http://127.0.0.1:1".to_string()),
+            None,
+        )
+        .unwrap();
+        let e = mock::new_address(&telegram, &mock::metadata("t", ""))
+            .await
+            .unwrap_err();
+        assert!(!e.to_string().contains("123:abc"), "{e}");
+    }
+}
@@ -0,0 +1,156 @@
+use crate::config::Metadata;
+use crate::watcher::{Event, PermanentError, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tracing::debug;
+use url::Url;
+
+/// Session id header.
+const SESSION_ID: &str = "X-Transmission-Session-Id";
+
+/// [Transmission](https://github.com/transmission/transmission/blob/main/docs/rpc-spec.md) RPC.
+pub struct Transmission {
+    /// Instance name.
+    name: String,
+    /// RPC url, e.g. `http://127.0.0.1:9091/transmission/rpc`.
+    url: Url,
+    /// Username and password of RPC.
+    credentials: Option<(String, String)>,
+    /// Session id to avoid CSRF attacks.
+    session_id: Mutex<Option<String>>,
+}
+
+#[derive(Serialize)]
+struct Request<T> {
+    method: &'static str,
+    arguments: T,
+}
+
+#[derive(Serialize)]
+struct SessionSet {
+    #[serde(rename = "peer-port")]
+    peer_port: u16,
+}
+
+#[derive(Deserialize)]
+struct ResponseBody {
+    result: String,
+}
+
+impl Transmission {
+    pub fn new(name: String, url: String, credentials: Option<(String, String)>) -> Result<Self> {
+        let url = Url::parse(url.as_str())?;
+        Ok(Self {
+            name,
+            url,
+            credentials,
+            session_id: Mutex::new(None),
+        })
+    }
+
+    async fn send<T: Serialize>(&self, req: &Request<T>) -> Result<Response> {
+        let client = reqwest::Client::new();
+        let mut builder = client.post(self.url.clone()).json(req);
+        if let Some((username, password)) = &self.credentials {
+            builder = builder.basic_auth(username, Some(password));
+        }
+        if let Some(session_id) = self.session_id.lock().unwrap().as_ref() {
+            builder = builder.header(SESSION_ID, session_id);
+        }
+        Ok(builder.send().await?)
+    }
+}
+
+#[async_trait]
+impl Watcher for Transmission {
+    fn kind(&self) -> &'static str {
+        "transmission"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, _: &Metadata) -> Result<()> {
+        let req = Request {
+            method: "session-set",
+            arguments: SessionSet {
+                peer_port: event.addr.port,
+            },
+        };
+        let mut resp = self.send(&req).await?;
+        if resp.status() == StatusCode::CONFLICT {
+            // Session id is missing or expired, retry with the new one.
+            let session_id = resp
+                .headers()
+                .get(SESSION_ID)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string());
+            if session_id.is_none() {
+                return Err(anyhow!("missing header `{SESSION_ID}`"));
+            }
+            *self.session_id.lock().unwrap() = session_id;
+            resp = self.send(&req).await?;
+        }
+        let status = resp.status();
+        if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+            return Err(PermanentError::new(status.to_string()).into());
+        }
+        let body: ResponseBody = resp.error_for_status()?.json().await?;
+        if body.result != "success" {
+            return Err(anyhow!("{}", body.result));
+        }
+        debug!(name = self.name(), "set peer port to {}", event.addr.port);
+        Ok(())
+    }
+
+    async fn validate(&self, _: &Metadata) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+
+    #[tokio::test]
+    async fn session_id_handshake() {
+        let (url, mut requests) = mock::serve(vec![
+            Response::new(409, "").header(SESSION_ID, "abc"),
+            Response::new(200, r#"{"result":"success","arguments":{}}"#),
+            Response::new(409, "").header(SESSION_ID, "def"),
+            Response::new(200, r#"{"result":"success","arguments":{}}"#),
+        ])
+        .await;
+        let transmission = Transmission::new(
+            "t".to_string(),
+            format!("{url}/transmission/rpc"),
+            Some(("admin".to_string(), "secret".to_string())),
+        )
+        .unwrap();
+        let md = mock::metadata("t", "");
+        mock::new_address(&transmission, &md).await.unwrap();
+
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.path, "/transmission/rpc");
+        assert_eq!(req.header(SESSION_ID), None);
+        assert_eq!(req.header("authorization"), Some("Basic YWRtaW46c2VjcmV0"));
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.header(SESSION_ID), Some("abc"));
+        assert_eq!(
+            req.json(),
+            serde_json::json!({"method": "session-set", "arguments": {"peer-port": 2222}})
+        );
+
+        // The session id expired.
+        mock::new_address(&transmission, &md).await.unwrap();
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.header(SESSION_ID), Some("abc"));
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.header(SESSION_ID), Some("def"));
+    }
+}
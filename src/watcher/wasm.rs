@@ -0,0 +1,520 @@
+use crate::config::Metadata;
+use crate::watcher::{Event, PermanentError, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bindings::nat2::watcher::http;
+use reqwest::redirect::Policy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::error::Elapsed;
+use tokio::time::timeout;
+use tracing::debug;
+use url::Url;
+use wasmtime::component::{Component, Linker, ResourceTable};
+use wasmtime::{Config, Engine, Store, StoreLimits, StoreLimitsBuilder};
+use wasmtime_wasi::{IoView, WasiCtx, WasiCtxBuilder, WasiView};
+
+mod bindings {
+    wasmtime::component::bindgen!({
+        path: "wit/watcher.wit",
+        world: "watcher",
+        async: true,
+    });
+}
+
+/// Default time in seconds to wait for a call to finish.
+const TIMEOUT: u64 = 30;
+
+/// The interval between epoch ticks, which lets a running call yield.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Default maximum size in MiB of the linear memory of a component instance.
+const MEMORY: usize = 64;
+
+/// Maximum number of redirects to follow.
+const MAX_REDIRECTS: usize = 10;
+
+/// A WebAssembly component implementing the `nat2:watcher` world in `wit/watcher.wit`.
+///
+/// The component is sandboxed. It has no access to the filesystem, environment or sockets,
+/// and can only reach the allowed origins through the `http` interface.
+pub struct Wasm {
+    /// Instance name.
+    name: String,
+    /// Compiled component.
+    component: Component,
+    /// Linker with WASI and the `http` interface.
+    linker: Linker<State>,
+    /// Origins which the component is allowed to send requests to.
+    allow: Arc<Vec<Origin>>,
+    /// The amount of time to wait for a call to finish.
+    timeout: Duration,
+    /// Maximum size in bytes of the linear memory.
+    memory: usize,
+    /// Epoch ticker, which runs while the watcher is started.
+    ticker: std::sync::Mutex<Option<Ticker>>,
+    /// Running instance, created on the first call.
+    instance: Mutex<Option<Instance>>,
+}
+
+/// Increments the epoch of the engine until it is dropped.
+/// It runs on its own thread, so a busy component is interrupted even if it
+/// occupies the only worker of the runtime.
+struct Ticker(Arc<AtomicBool>);
+
+impl Ticker {
+    fn spawn(engine: Engine) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let flag = stopped.clone();
+        std::thread::spawn(move || {
+            while !flag.load(Ordering::Relaxed) {
+                std::thread::sleep(TICK);
+                engine.increment_epoch();
+            }
+        });
+        Self(stopped)
+    }
+}
+
+impl Drop for Ticker {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Store and exports of a component instance.
+type Instance = (Store<State>, bindings::Watcher);
+
+/// Host state of a component instance.
+struct State {
+    ctx: WasiCtx,
+    table: ResourceTable,
+    limits: StoreLimits,
+    fetch: Fetch,
+}
+
+/// An entry of the allow list, e.g. `https://api.example.com` or `http://*.example.com:8080`.
+struct Origin {
+    scheme: String,
+    /// Host name. If it starts with `*.`, all of the subdomains are matched.
+    host: String,
+    port: u16,
+}
+
+impl Origin {
+    /// Parse an allow list entry. The scheme defaults to `https`,
+    /// and the port defaults to the one of the scheme.
+    fn parse(s: &str) -> Result<Self> {
+        let url = match s.contains("://") {
+            true => Url::parse(s),
+            false => Url::parse(&format!("https://{s}")),
+        }
+        .map_err(|e| anyhow!("{e} in allow entry `{s}`"))?;
+        if !matches!(url.scheme(), "http" | "https")
+            || url.path() != "/"
+            || url.query().is_some()
+            || !url.username().is_empty()
+        {
+            return Err(anyhow!(
+                "invalid allow entry `{s}`, expected `[scheme://]host[:port]`"
+            ));
+        }
+        Ok(Self {
+            scheme: url.scheme().to_string(),
+            host: url.host_str().unwrap_or_default().to_string(),
+            port: url.port_or_known_default().unwrap_or_default(),
+        })
+    }
+
+    /// Whether the url is of this origin.
+    fn matches(&self, url: &Url) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        let host_matched = match self.host.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|v| v.ends_with('.')),
+            None => self.host == host,
+        };
+        host_matched
+            && self.scheme == url.scheme()
+            && Some(self.port) == url.port_or_known_default()
+    }
+}
+
+/// Implementation of the `http` interface.
+#[derive(Clone)]
+struct Fetch {
+    allow: Arc<Vec<Origin>>,
+    client: reqwest::Client,
+}
+
+impl IoView for State {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+}
+
+impl WasiView for State {
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.ctx
+    }
+}
+
+/// Whether the url is of an origin in the allow list.
+fn allowed(allow: &[Origin], url: &Url) -> bool {
+    allow.iter().any(|v| v.matches(url))
+}
+
+impl Fetch {
+    fn new(allow: Arc<Vec<Origin>>) -> Result<Self> {
+        // Every hop of the redirects is checked against the allow list.
+        let policy = {
+            let allow = allow.clone();
+            Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if !allowed(&allow, attempt.url()) {
+                    let e = anyhow!(
+                        "redirect to `{}` is not allowed",
+                        attempt.url().origin().ascii_serialization()
+                    );
+                    attempt.error(e)
+                } else {
+                    attempt.follow()
+                }
+            })
+        };
+        let client = reqwest::Client::builder().redirect(policy).build()?;
+        Ok(Self { allow, client })
+    }
+
+    async fn send(&self, req: http::Request) -> Result<http::Response> {
+        let url = Url::parse(req.url.as_str())?;
+        if !allowed(&self.allow, &url) {
+            return Err(anyhow!(
+                "origin `{}` is not allowed",
+                url.origin().ascii_serialization()
+            ));
+        }
+        let mut builder = self.client.request(req.method.parse()?, url).body(req.body);
+        for (key, value) in req.headers {
+            builder = builder.header(key, value);
+        }
+        let resp = builder.send().await.map_err(|e| {
+            // The error of the redirect policy is the source.
+            match std::error::Error::source(&e) {
+                Some(source) if e.is_redirect() => anyhow!("{source}"),
+                _ => anyhow!(e),
+            }
+        })?;
+        let status = resp.status().as_u16();
+        let headers = resp
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or_default().to_string()))
+            .collect();
+        let body = resp.bytes().await?.to_vec();
+        Ok(http::Response {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+impl http::Host for State {
+    async fn fetch(&mut self, req: http::Request) -> Result<http::Response, String> {
+        // The WASI context is not `Sync`, so don't hold it across the request.
+        let fetch = self.fetch.clone();
+        fetch.send(req).await.map_err(|e| e.to_string())
+    }
+}
+
+impl bindings::nat2::watcher::types::Host for State {}
+
+impl Wasm {
+    pub fn new(
+        name: String,
+        path: String,
+        allow: Vec<String>,
+        timeout: Option<u64>,
+        memory: Option<usize>,
+    ) -> Result<Self> {
+        let allow = allow
+            .iter()
+            .map(|v| Origin::parse(v))
+            .collect::<Result<Vec<_>>>()?;
+        let mut config = Config::new();
+        config.async_support(true).epoch_interruption(true);
+        let engine = Engine::new(&config)?;
+        let component = Component::from_file(&engine, path)?;
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::add_to_linker_async(&mut linker)?;
+        bindings::Watcher::add_to_linker(&mut linker, |state: &mut State| state)?;
+        Ok(Self {
+            name,
+            component,
+            linker,
+            allow: Arc::new(allow),
+            timeout: Duration::from_secs(timeout.filter(|v| *v > 0).unwrap_or(TIMEOUT)),
+            memory: memory.filter(|v| *v > 0).unwrap_or(MEMORY) << 20,
+            ticker: std::sync::Mutex::new(None),
+            instance: Mutex::new(None),
+        })
+    }
+
+    /// Whether the watcher is started. The component is not run before that,
+    /// e.g. when the configuration is only checked.
+    fn started(&self) -> bool {
+        self.ticker.lock().unwrap().is_some()
+    }
+
+    /// Create a new instance of the component.
+    async fn instantiate(&self) -> Result<Instance> {
+        let state = State {
+            ctx: WasiCtxBuilder::new().inherit_stderr().build(),
+            table: ResourceTable::new(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(self.memory)
+                .trap_on_grow_failure(true)
+                .build(),
+            fetch: Fetch::new(self.allow.clone())?,
+        };
+        let mut store = Store::new(self.component.engine(), state);
+        store.limiter(|state| &mut state.limits);
+        store.epoch_deadline_async_yield_and_update(1);
+        let watcher =
+            bindings::Watcher::instantiate_async(&mut store, &self.component, &self.linker).await?;
+        let kind = watcher.call_kind(&mut store).await?;
+        debug!(name = self.name(), kind, "component instantiated");
+        Ok((store, watcher))
+    }
+
+    /// Lock the instance, instantiating the component if necessary.
+    async fn lock(&self) -> Result<MutexGuard<'_, Option<Instance>>> {
+        let mut instance = self.instance.lock().await;
+        if instance.is_none() {
+            if !self.started() {
+                return Err(anyhow!("watcher is not started"));
+            }
+            *instance = Some(self.instantiate().await?);
+        }
+        Ok(instance)
+    }
+
+    /// Wait for a call to finish within the timeout.
+    /// The instance is recreated on the next call if it traps or does not finish in time.
+    fn wait<T>(
+        &self,
+        instance: &mut Option<Instance>,
+        result: Result<Result<T>, Elapsed>,
+    ) -> Result<T> {
+        let result = result
+            .map_err(|_| anyhow!("call timed out after {}s", self.timeout.as_secs()))
+            .and_then(|v| v);
+        if result.is_err() {
+            *instance = None;
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Watcher for Wasm {
+    fn kind(&self) -> &'static str {
+        "wasm"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let ip = event.addr.ip.to_string();
+        let port = event.addr.port;
+        let md = serde_json::to_string(md)?;
+        let mut instance = self.lock().await?;
+        let (store, watcher) = instance.as_mut().unwrap();
+        let call = watcher.call_new_address(store, ip.as_str(), port, md.as_str());
+        let result = timeout(self.timeout, call).await;
+        let result = self.wait(&mut instance, result)?;
+        result.map_err(|e| {
+            if e.permanent {
+                PermanentError::new(e.message).into()
+            } else {
+                anyhow!(e.message)
+            }
+        })
+    }
+
+    async fn validate(&self, md: &Metadata) -> Result<()> {
+        // The metadata is validated by the component when it is started.
+        if !self.started() {
+            return Ok(());
+        }
+        let md = serde_json::to_string(md)?;
+        let mut instance = self.lock().await?;
+        let (store, watcher) = instance.as_mut().unwrap();
+        let result = timeout(self.timeout, watcher.call_validate(store, md.as_str())).await;
+        let result = self.wait(&mut instance, result)?;
+        result.map_err(|e| anyhow!(e))
+    }
+
+    fn validates_when_started(&self) -> bool {
+        true
+    }
+
+    async fn start(&self) -> Result<()> {
+        let mut ticker = self.ticker.lock().unwrap();
+        if ticker.is_some() {
+            return Ok(());
+        }
+        *ticker = Some(Ticker::spawn(self.component.engine().clone()));
+        Ok(())
+    }
+
+    async fn stop(&self) {
+        // Wait for the running call, which needs the ticker to be interrupted.
+        self.instance.lock().await.take();
+        self.ticker.lock().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+    use stun::xoraddr::XorMappedAddress;
+
+    /// Returns a watcher running the test component in `wit/watcher.wat`,
+    /// with a timeout of 1 second and a memory limit of 1 MiB.
+    fn component(name: &str) -> Wasm {
+        let path = mock::temp_path(name);
+        let wasm = wat::parse_file("wit/watcher.wat").unwrap();
+        std::fs::write(&path, wasm).unwrap();
+        let wasm = Wasm::new(
+            "w".to_string(),
+            path.display().to_string(),
+            Vec::new(),
+            Some(1),
+            Some(1),
+        )
+        .unwrap();
+        let _ = std::fs::remove_file(&path);
+        wasm
+    }
+
+    /// Notify the component of a new address with the port.
+    async fn call(wasm: &Wasm, port: u16) -> Result<()> {
+        let addr = XorMappedAddress {
+            ip: mock::ADDR.ip,
+            port,
+        };
+        let event = Event {
+            addr: &addr,
+            ..mock::event(None)
+        };
+        wasm.new_address(&event, &mock::metadata("w", "")).await
+    }
+
+    fn origins(allow: &[&str]) -> Arc<Vec<Origin>> {
+        Arc::new(allow.iter().map(|v| Origin::parse(v).unwrap()).collect())
+    }
+
+    fn get(url: &str) -> http::Request {
+        http::Request {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn allow_list() {
+        let allow = origins(&["api.example.com", "http://*.example.org:8080"]);
+        for (url, expected) in [
+            ("https://api.example.com/v1", true),
+            ("https://api.example.com:443/v1", true),
+            ("http://api.example.com/v1", false),
+            ("https://api.example.com:8443/v1", false),
+            ("https://www.example.com/", false),
+            ("http://a.b.example.org:8080/", true),
+            ("http://example.org:8080/", false),
+            ("http://a.example.org/", false),
+            ("https://a.example.org:8080/", false),
+        ] {
+            assert_eq!(
+                allowed(&allow, &Url::parse(url).unwrap()),
+                expected,
+                "{url}"
+            );
+        }
+        assert!(Origin::parse("ftp://example.com").is_err());
+        assert!(Origin::parse("https://example.com/path").is_err());
+    }
+
+    #[tokio::test]
+    async fn redirect_checked() {
+        let (target, mut requests) = mock::serve(vec![Response::new(200, "ok")]).await;
+        let (url, _) = mock::serve(vec![
+            Response::new(302, "").header("location", &format!("{target}/a")),
+            Response::new(302, "").header("location", &format!("{target}/b")),
+        ])
+        .await;
+        // The redirect target is on another port.
+        let fetch = Fetch::new(origins(&[url.as_str()])).unwrap();
+        let e = fetch.send(get(&url)).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!("redirect to `{target}` is not allowed")
+        );
+
+        let fetch = Fetch::new(origins(&[url.as_str(), target.as_str()])).unwrap();
+        let resp = fetch.send(get(&url)).await.unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body, b"ok");
+        assert_eq!(requests.recv().await.unwrap().path, "/b");
+    }
+
+    #[tokio::test]
+    async fn component_calls() {
+        let wasm = component("wasm-calls.wasm");
+        // The component is not run before the watcher is started.
+        wasm.validate(&mock::metadata("w", "!")).await.unwrap();
+        let e = call(&wasm, 2222).await.unwrap_err();
+        assert_eq!(e.to_string(), "watcher is not started");
+
+        wasm.start().await.unwrap();
+        wasm.validate(&mock::metadata("w", "{ip}")).await.unwrap();
+        let e = wasm.validate(&mock::metadata("w", "!")).await.unwrap_err();
+        assert_eq!(e.to_string(), "invalid value");
+        mock::new_address(&wasm, &mock::metadata("w", ""))
+            .await
+            .unwrap();
+        wasm.stop().await;
+    }
+
+    #[tokio::test]
+    async fn component_limits() {
+        let wasm = component("wasm-limits.wasm");
+        wasm.start().await.unwrap();
+        let e = call(&wasm, 1).await.unwrap_err();
+        assert_eq!(e.to_string(), "call timed out after 1s");
+        // The instance is recreated after a call fails.
+        call(&wasm, 2222).await.unwrap();
+        assert!(call(&wasm, 2).await.is_err());
+        call(&wasm, 2222).await.unwrap();
+        wasm.stop().await;
+    }
+
+    #[tokio::test]
+    async fn component_sandboxed() {
+        let wasm = component("wasm-sandboxed.wasm");
+        wasm.start().await.unwrap();
+        let e = call(&wasm, 3).await.unwrap_err();
+        assert!(e.is::<PermanentError>(), "{e}");
+        assert_eq!(e.to_string(), "origin `http://127.0.0.1:9` is not allowed");
+        wasm.stop().await;
+    }
+}
@@ -0,0 +1,121 @@
+use crate::config::Metadata;
+use crate::watcher::{chat, Event, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde_json::json;
+use tracing::debug;
+use url::Url;
+
+/// Payload format of the incoming webhook.
+enum Format {
+    /// `{"text": "..."}`, also accepted by Mattermost, Rocket.Chat, etc.
+    Slack,
+    /// `{"content": "..."}`.
+    Discord,
+}
+
+/// Slack or Discord style incoming webhook.
+pub struct Webhook {
+    /// Instance name.
+    name: String,
+    /// Webhook url.
+    url: Url,
+    /// Payload format.
+    format: Format,
+    /// Message template.
+    message: Option<String>,
+}
+
+impl Webhook {
+    pub fn new(
+        name: String,
+        url: String,
+        format: Option<String>,
+        message: Option<String>,
+    ) -> Result<Self> {
+        let url = Url::parse(url.as_str())?;
+        let format = match format.as_deref().unwrap_or("slack") {
+            "slack" => Format::Slack,
+            "discord" => Format::Discord,
+            v => return Err(anyhow!("unsupported webhook format `{v}`")),
+        };
+        Ok(Self {
+            name,
+            url,
+            format,
+            message,
+        })
+    }
+}
+
+#[async_trait]
+impl Watcher for Webhook {
+    fn kind(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let client = reqwest::Client::new();
+        let text = chat::message(&self.message, event, md);
+        let payload = match self.format {
+            Format::Slack => json!({ "text": text }),
+            Format::Discord => json!({ "content": text }),
+        };
+        let req = client.post(self.url.clone()).json(&payload);
+        let resp = chat::send(self.name(), req).await?;
+        debug!(
+            code = resp.status().as_str(),
+            name = self.name(),
+            "message sent"
+        );
+        chat::check(resp).await
+    }
+
+    async fn validate(&self, _: &Metadata) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+    use crate::watcher::PermanentError;
+
+    #[tokio::test]
+    async fn payload_format() {
+        let (url, mut requests) = mock::serve(vec![
+            Response::new(200, "ok"),
+            Response::new(204, ""),
+            Response::new(400, "invalid_payload"),
+        ])
+        .await;
+        let event = mock::event(None);
+        let md = mock::metadata("w", "{ip}:{port}");
+        let slack = Webhook::new("w".to_string(), url.clone(), None, None).unwrap();
+        slack.new_address(&event, &md).await.unwrap();
+        let discord =
+            Webhook::new("w".to_string(), url, Some("discord".to_string()), None).unwrap();
+        discord.new_address(&event, &md).await.unwrap();
+        // Client errors are not retried.
+        let e = slack.new_address(&event, &md).await.unwrap_err();
+        assert!(e.is::<PermanentError>());
+        assert_eq!(e.to_string(), "400 Bad Request: invalid_payload");
+
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.json(), serde_json::json!({"text": "2.2.2.2:2222"}));
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.json(), serde_json::json!({"content": "2.2.2.2:2222"}));
+    }
+
+    #[test]
+    fn unknown_format() {
+        let url = "http://127.0.0.1".to_string();
+        assert!(Webhook::new("w".to_string(), url, Some("teams".to_string()), None).is_err());
+    }
+}
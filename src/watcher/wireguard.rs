@@ -0,0 +1,658 @@
+use crate::config::Metadata;
+use crate::watcher::{format_value, Event, PermanentError, Watcher};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::StatusCode;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tracing::{debug, error, info};
+use url::Url;
+
+/// Directory of the UAPI sockets created by userspace implementations.
+const UAPI_DIR: &str = "/var/run/wireguard";
+
+/// Header of the hex encoded HMAC-SHA256 signature of the request body.
+const SIGNATURE: &str = "X-Nat2-Signature";
+
+/// The maximum difference (in milliseconds) between the clocks of both sites.
+const MAX_SKEW: u64 = 300_000;
+
+/// The maximum size (in bytes) of a pushed request.
+const MAX_REQUEST: u64 = 4096;
+
+/// The amount of time (in seconds) to wait for a pushed request.
+const REQUEST_TIMEOUT: u64 = 10;
+
+/// Keep the peer endpoints of site-to-site [WireGuard](https://www.wireguard.com) tunnels
+/// up to date.
+///
+/// Both sites run nat2. The mapped address of the local WireGuard port is signed and pushed
+/// to the `remote` nat2, which listens on `listen` and sets it as the endpoint of the peer
+/// with our public key in its `interface`.
+pub struct WireGuard {
+    /// Instance name.
+    name: String,
+    /// Url of the remote nat2 to push the mapped address to.
+    remote: Option<Url>,
+    /// Key to sign the pushed endpoints.
+    key: hmac::Key,
+    /// Accepts the endpoints pushed by the remote nat2.
+    receiver: Option<Arc<Receiver>>,
+    /// Server of the receiver, running while the watcher is started.
+    server: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Request body of a pushed endpoint.
+#[derive(Debug, Deserialize, Serialize)]
+struct Push {
+    /// Public key of the peer in the remote interface.
+    peer: String,
+    /// New endpoint of the peer.
+    endpoint: String,
+    /// Unix time in milliseconds when the request was signed.
+    time: u64,
+}
+
+impl WireGuard {
+    pub fn new(
+        name: String,
+        remote: Option<String>,
+        listen: Option<String>,
+        interface: Option<String>,
+        token: String,
+    ) -> Result<Self> {
+        let remote = remote.map(|v| Url::parse(v.as_str())).transpose()?;
+        if let Some(url) = &remote {
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(anyhow!("unsupported scheme `{}`", url.scheme()));
+            }
+        }
+        let listen = listen
+            .map(|v| {
+                v.parse::<SocketAddr>()
+                    .map_err(|e| anyhow!("invalid listen address `{v}`: {e}"))
+            })
+            .transpose()?;
+        if remote.is_none() && listen.is_none() {
+            return Err(anyhow!("either `remote` or `listen` is required"));
+        }
+        let key = hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes());
+        let receiver = match (listen, interface) {
+            (Some(listen), Some(interface)) => Some(Arc::new(Receiver {
+                listen,
+                interface: Interface::new(interface),
+                key: key.clone(),
+                seen: tokio::sync::Mutex::new(HashMap::new()),
+            })),
+            (None, None) => None,
+            _ => return Err(anyhow!("`listen` and `interface` must be set together")),
+        };
+        Ok(Self {
+            name,
+            remote,
+            key,
+            receiver,
+            server: Mutex::new(None),
+        })
+    }
+
+    /// Sign the endpoint and push it to the remote nat2.
+    async fn push(&self, remote: &Url, peer: &str, endpoint: &str) -> Result<()> {
+        let body = serde_json::to_vec(&Push {
+            peer: peer.to_string(),
+            endpoint: endpoint.to_string(),
+            time: now(),
+        })?;
+        let signature = hex::encode(hmac::sign(&self.key, &body));
+        let resp = reqwest::Client::new()
+            .post(remote.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .header(SIGNATURE, signature)
+            .body(body)
+            .send()
+            .await?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let e = format!("{status}: {}", resp.text().await?.trim());
+        match status {
+            // Wrong token, clocks out of sync, or no such peer in the remote interface.
+            StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED | StatusCode::NOT_FOUND => {
+                Err(PermanentError::new(e).into())
+            }
+            _ => Err(anyhow!(e)),
+        }
+    }
+}
+
+impl Drop for WireGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = self.server.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+}
+
+/// Returns the current unix time in milliseconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |v| v.as_millis() as u64)
+}
+
+/// A local WireGuard interface.
+///
+/// The userspace implementations (e.g. `wireguard-go` and `boringtun`) are configured
+/// through the UAPI socket, and the kernel module is configured by the `wg` tool.
+struct Interface {
+    /// Interface name, e.g. `wg0`.
+    name: String,
+    /// Path to the UAPI socket of the interface.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    uapi: PathBuf,
+}
+
+impl Interface {
+    fn new(name: String) -> Self {
+        let uapi = PathBuf::from(UAPI_DIR).join(format!("{name}.sock"));
+        Self { name, uapi }
+    }
+
+    /// Set the endpoint with the UAPI socket if it exists.
+    /// Returns `None` if the interface is not managed by a userspace implementation.
+    #[cfg(unix)]
+    async fn set_uapi(&self, key: &[u8], endpoint: &str) -> Option<Result<()>> {
+        use tokio::net::UnixStream;
+
+        if !self.uapi.exists() {
+            return None;
+        }
+        let set = async {
+            let mut stream = UnixStream::connect(&self.uapi).await?;
+            // Don't create the peer if it is not in the interface.
+            let req = format!(
+                "set=1\npublic_key={}\nupdate_only=true\nendpoint={endpoint}\n\n",
+                hex::encode(key)
+            );
+            stream.write_all(req.as_bytes()).await?;
+            let mut lines = BufReader::new(stream).lines();
+            while let Some(line) = lines.next_line().await? {
+                if let Some(errno) = line.strip_prefix("errno=") {
+                    return match errno {
+                        "0" => Ok(()),
+                        _ => Err(anyhow!("uapi returned errno {errno}")),
+                    };
+                }
+            }
+            Err(anyhow!("uapi closed the connection unexpectedly"))
+        };
+        Some(set.await)
+    }
+
+    #[cfg(not(unix))]
+    async fn set_uapi(&self, _: &[u8], _: &str) -> Option<Result<()>> {
+        None
+    }
+
+    /// Run the `wg` tool and return its standard output.
+    async fn wg(&self, args: &[&str]) -> Result<String> {
+        let output = Command::new("wg").args(args).output().await?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "wg {}: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Set the endpoint with the `wg` tool.
+    async fn set_command(&self, key: &str, endpoint: &str) -> Result<()> {
+        // `wg set` creates the peer if it is not in the interface.
+        let peers = self.wg(&["show", &self.name, "peers"]).await?;
+        if !peers.lines().any(|v| v.trim() == key) {
+            return Err(
+                PermanentError::new(format!("no peer `{key}` in interface {}", self.name)).into(),
+            );
+        }
+        self.wg(&["set", &self.name, "peer", key, "endpoint", endpoint])
+            .await?;
+        Ok(())
+    }
+
+    /// Set the endpoint of the peer.
+    async fn set(&self, peer: &str, endpoint: SocketAddr) -> Result<()> {
+        let endpoint = endpoint.to_string();
+        match self.set_uapi(&decode_key(peer)?, &endpoint).await {
+            Some(result) => result,
+            None => self.set_command(peer, &endpoint).await,
+        }
+    }
+}
+
+/// Listen on the address.
+/// The watcher replaced by a reload is stopped after the new one is started,
+/// so the port is shared with it until then.
+fn listen(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Decode the base64 public key of the peer.
+fn decode_key(key: &str) -> Result<Vec<u8>> {
+    let key = BASE64_STANDARD
+        .decode(key)
+        .map_err(|e| anyhow!("invalid public key `{key}`: {e}"))?;
+    if key.len() != 32 {
+        return Err(anyhow!("invalid public key length {}", key.len()));
+    }
+    Ok(key)
+}
+
+/// Accepts the endpoints pushed by the remote nat2 and sets them in the local interface.
+struct Receiver {
+    /// Address to listen on.
+    listen: SocketAddr,
+    interface: Interface,
+    /// Key to verify the signatures.
+    key: hmac::Key,
+    /// Time of the last applied request of each peer, so old requests can't be replayed.
+    /// It is locked while a request is applied, so a duplicate or an older request
+    /// can't overtake a newer one.
+    seen: tokio::sync::Mutex<HashMap<String, u64>>,
+}
+
+impl Receiver {
+    /// Accept the connections until the task is aborted.
+    async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!(interface = self.interface.name, "{e}");
+                    continue;
+                }
+            };
+            let receiver = self.clone();
+            tokio::spawn(async move {
+                let served = timeout(
+                    Duration::from_secs(REQUEST_TIMEOUT),
+                    receiver.handle(stream),
+                );
+                match served.await {
+                    Ok(Err(e)) => debug!(interface = receiver.interface.name, "{e}"),
+                    Err(_) => debug!(interface = receiver.interface.name, "request timed out"),
+                    Ok(Ok(_)) => {}
+                }
+            });
+        }
+    }
+
+    /// Read a single HTTP request and answer it.
+    async fn handle(&self, stream: TcpStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader).take(MAX_REQUEST);
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let method = line
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        let mut signature = None;
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            let Some((k, v)) = line.trim_end().split_once(':') else {
+                break;
+            };
+            if k.eq_ignore_ascii_case(SIGNATURE) {
+                signature = Some(v.trim().to_string());
+            } else if k.eq_ignore_ascii_case("content-length") {
+                len = v.trim().parse()?;
+            }
+        }
+        let (status, message) = if method != "POST" {
+            (StatusCode::METHOD_NOT_ALLOWED, String::new())
+        } else if len > MAX_REQUEST as usize {
+            (StatusCode::PAYLOAD_TOO_LARGE, String::new())
+        } else {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).await?;
+            match self.apply(signature.as_deref(), &body).await {
+                Ok(_) => (StatusCode::NO_CONTENT, String::new()),
+                Err(e) => e,
+            }
+        };
+        let resp = format!(
+            "HTTP/1.1 {} {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{message}",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default(),
+            message.len()
+        );
+        writer.write_all(resp.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Check the signature and the time of the request.
+    fn verify(&self, signature: Option<&str>, body: &[u8]) -> Result<Push, (StatusCode, String)> {
+        let unauthorized = |e: &str| (StatusCode::UNAUTHORIZED, e.to_string());
+        let signature = signature
+            .and_then(|v| hex::decode(v).ok())
+            .ok_or(unauthorized("missing signature"))?;
+        hmac::verify(&self.key, body, &signature).map_err(|_| unauthorized("invalid signature"))?;
+        let push: Push =
+            serde_json::from_slice(body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        if now().abs_diff(push.time) > MAX_SKEW {
+            return Err(unauthorized(
+                "request expired, check the clocks of both sites",
+            ));
+        }
+        Ok(push)
+    }
+
+    /// Set the pushed endpoint in the interface.
+    async fn apply(
+        &self,
+        signature: Option<&str>,
+        body: &[u8],
+    ) -> Result<(), (StatusCode, String)> {
+        let push = self.verify(signature, body)?;
+        let bad_request = |e: anyhow::Error| (StatusCode::BAD_REQUEST, e.to_string());
+        decode_key(&push.peer).map_err(bad_request)?;
+        let endpoint: SocketAddr = push
+            .endpoint
+            .parse()
+            .map_err(|e| bad_request(anyhow!("invalid endpoint `{}`: {e}", push.endpoint)))?;
+        let mut seen = self.seen.lock().await;
+        if seen.get(&push.peer).is_some_and(|last| *last >= push.time) {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "request is older than the applied one".to_string(),
+            ));
+        }
+        if let Err(e) = self.interface.set(&push.peer, endpoint).await {
+            error!(interface = self.interface.name, "{e}");
+            return Err(match e.is::<PermanentError>() {
+                true => (StatusCode::NOT_FOUND, e.to_string()),
+                false => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+            });
+        }
+        seen.insert(push.peer.clone(), push.time);
+        info!(
+            interface = self.interface.name,
+            "set endpoint of peer {} to {endpoint}", push.peer
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Watcher for WireGuard {
+    fn kind(&self) -> &'static str {
+        "wireguard"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    async fn new_address(&self, event: &Event<'_>, md: &Metadata) -> Result<()> {
+        let remote = self.remote.as_ref().ok_or(PermanentError::new(
+            "missing field `remote` to push the address to",
+        ))?;
+        let peer = md.peer.as_ref().unwrap();
+        let endpoint = if md.value.is_empty() {
+            SocketAddr::new(event.addr.ip, event.addr.port).to_string()
+        } else {
+            format_value(&md.value, event.addr)
+        };
+        self.push(remote, peer, &endpoint).await?;
+        debug!(
+            name = self.name(),
+            remote = remote.as_str(),
+            "pushed endpoint {endpoint} of peer {peer}"
+        );
+        Ok(())
+    }
+
+    async fn validate(&self, md: &Metadata) -> Result<()> {
+        if self.remote.is_none() {
+            return Err(anyhow!("missing field `remote` to push the address to"));
+        }
+        let peer = md.peer.as_ref().ok_or(anyhow!("missing field `peer`"))?;
+        decode_key(peer)?;
+        Ok(())
+    }
+
+    async fn start(&self) -> Result<()> {
+        let Some(receiver) = &self.receiver else {
+            return Ok(());
+        };
+        let listener =
+            listen(receiver.listen).map_err(|e| anyhow!("listen on {}: {e}", receiver.listen))?;
+        let handle = tokio::spawn(receiver.clone().serve(listener));
+        if let Some(old) = self.server.lock().unwrap().replace(handle) {
+            old.abort();
+        }
+        Ok(())
+    }
+
+    async fn stop(&self) {
+        if let Some(handle) = self.server.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watcher::mock::{self, Response};
+
+    const PEER: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
+    fn receiver(token: &str) -> Receiver {
+        Receiver {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            interface: Interface::new("wg0".to_string()),
+            key: hmac::Key::new(hmac::HMAC_SHA256, token.as_bytes()),
+            seen: tokio::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the signature and the body of a pushed endpoint.
+    fn sign(receiver: &Receiver, endpoint: &str, time: u64) -> (String, Vec<u8>) {
+        let body = serde_json::to_vec(&Push {
+            peer: PEER.to_string(),
+            endpoint: endpoint.to_string(),
+            time,
+        })
+        .unwrap();
+        (hex::encode(hmac::sign(&receiver.key, &body)), body)
+    }
+
+    /// Serve a fake UAPI socket of the receiver interface, which answers the first
+    /// request after the delay. Returns the endpoints in the order they are set.
+    #[cfg(unix)]
+    fn uapi(receiver: &mut Receiver, name: &str, delay: Duration) -> Arc<Mutex<Vec<String>>> {
+        let path = mock::temp_path(name);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        receiver.interface.uapi = path;
+        let endpoints = Arc::new(Mutex::new(Vec::new()));
+        let set = endpoints.clone();
+        tokio::spawn(async move {
+            for i in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let set = set.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut endpoint = String::new();
+                    loop {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await.unwrap();
+                        match line.trim_end() {
+                            "" => break,
+                            v => {
+                                if let Some(v) = v.strip_prefix("endpoint=") {
+                                    endpoint = v.to_string();
+                                }
+                            }
+                        }
+                    }
+                    if i == 0 {
+                        tokio::time::sleep(delay).await;
+                    }
+                    set.lock().unwrap().push(endpoint);
+                    stream.write_all(b"errno=0\n\n").await.unwrap();
+                });
+            }
+        });
+        endpoints
+    }
+
+    #[tokio::test]
+    async fn push_signed() {
+        let (url, mut requests) = mock::serve(vec![Response::new(204, "")]).await;
+        let wireguard = WireGuard::new(
+            "wg".to_string(),
+            Some(url),
+            None,
+            None,
+            "secret".to_string(),
+        )
+        .unwrap();
+        let md =
+            serde_json::from_value(serde_json::json!({"name": "wg", "value": "", "peer": PEER}))
+                .unwrap();
+        wireguard.validate(&md).await.unwrap();
+        mock::new_address(&wireguard, &md).await.unwrap();
+
+        let req = requests.recv().await.unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.json()["peer"], PEER);
+        assert_eq!(req.json()["endpoint"], "2.2.2.2:2222");
+        let signature = req.header(SIGNATURE);
+        let push = receiver("secret")
+            .verify(signature, req.body.as_bytes())
+            .unwrap();
+        assert_eq!(push.endpoint, "2.2.2.2:2222");
+        let (status, _) = receiver("wrong")
+            .verify(signature, req.body.as_bytes())
+            .unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn expired_request_rejected() {
+        let receiver = receiver("secret");
+        let (signature, body) = sign(&receiver, "2.2.2.2:2222", now() - MAX_SKEW - 1000);
+        let (status, e) = receiver.verify(Some(&signature), &body).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{e}");
+        let (signature, body) = sign(&receiver, "2.2.2.2:2222", now());
+        assert!(receiver.verify(Some(&signature), &body).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn duplicate_request_rejected() {
+        let mut receiver = receiver("secret");
+        let endpoints = uapi(
+            &mut receiver,
+            "wg-duplicate.sock",
+            Duration::from_millis(200),
+        );
+        let (signature, body) = sign(&receiver, "2.2.2.2:2222", now());
+        let (a, b) = tokio::join!(
+            receiver.apply(Some(&signature), &body),
+            receiver.apply(Some(&signature), &body)
+        );
+        assert!(a.is_ok() != b.is_ok(), "{a:?} {b:?}");
+        let (status, e) = a.and(b).unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{e}");
+        assert_eq!(*endpoints.lock().unwrap(), ["2.2.2.2:2222"]);
+        // An older request is rejected after the newer one is applied.
+        let (signature, body) = sign(&receiver, "1.1.1.1:1111", now() - 1000);
+        let (status, _) = receiver.apply(Some(&signature), &body).await.unwrap_err();
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let _ = std::fs::remove_file(&receiver.interface.uapi);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn newer_request_applied_last() {
+        let mut receiver = receiver("secret");
+        let endpoints = uapi(&mut receiver, "wg-order.sock", Duration::from_millis(200));
+        let receiver = Arc::new(receiver);
+        let time = now();
+        let (old_signature, old_body) = sign(&receiver, "1.1.1.1:1111", time);
+        let (signature, body) = sign(&receiver, "2.2.2.2:2222", time + 1);
+        // The older request is slow to be set.
+        let old = tokio::spawn({
+            let receiver = receiver.clone();
+            async move { receiver.apply(Some(&old_signature), &old_body).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        receiver.apply(Some(&signature), &body).await.unwrap();
+        old.await.unwrap().unwrap();
+        assert_eq!(*endpoints.lock().unwrap(), ["1.1.1.1:1111", "2.2.2.2:2222"]);
+        let _ = std::fs::remove_file(&receiver.interface.uapi);
+    }
+
+    #[tokio::test]
+    async fn start_fails_if_port_in_use() {
+        let used = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let wireguard = WireGuard::new(
+            "wg".to_string(),
+            None,
+            Some(used.local_addr().unwrap().to_string()),
+            Some("wg0".to_string()),
+            "secret".to_string(),
+        )
+        .unwrap();
+        let e = wireguard.start().await.unwrap_err();
+        assert!(e.to_string().starts_with("listen on"), "{e}");
+        drop(used);
+        wireguard.start().await.unwrap();
+        wireguard.stop().await;
+    }
+
+    #[test]
+    fn listen_requires_interface() {
+        let new = |listen: Option<&str>, interface: Option<&str>| {
+            WireGuard::new(
+                "wg".to_string(),
+                None,
+                listen.map(String::from),
+                interface.map(String::from),
+                "secret".to_string(),
+            )
+        };
+        assert!(new(None, None).is_err());
+        assert!(new(Some("0.0.0.0:51821"), None).is_err());
+        assert!(new(None, Some("wg0")).is_err());
+        assert!(new(Some("0.0.0.0:51821"), Some("wg0")).is_ok());
+    }
+}
@@ -0,0 +1,123 @@
+;; A minimal component of the `watcher` world in `watcher.wit`, used by the tests.
+;;
+;; `validate` rejects the metadata which contains `!`.
+;; `new-address` depends on the port:
+;;   1 never returns,
+;;   2 grows the memory by 4 MiB,
+;;   3 fetches `http://127.0.0.1:9/` and fails permanently with the error,
+;;   and the others succeed.
+(component
+  (import "nat2:watcher/types@0.1.0" (instance $types
+    (type $error (record (field "message" string) (field "permanent" bool)))
+    (export "error" (type (eq $error)))
+  ))
+  (alias export $types "error" (type $error))
+
+  (import "nat2:watcher/http@0.1.0" (instance $http
+    (type $headers (list (tuple string string)))
+    (type $request' (record
+      (field "method" string)
+      (field "url" string)
+      (field "headers" $headers)
+      (field "body" (list u8))
+    ))
+    (export "request" (type $request (eq $request')))
+    (type $response' (record
+      (field "status" u16)
+      (field "headers" $headers)
+      (field "body" (list u8))
+    ))
+    (export "response" (type $response (eq $response')))
+    (export "fetch" (func (param "request" $request) (result (result $response (error string)))))
+  ))
+
+  ;; Memory and allocator shared by the lowered imports and the lifted exports.
+  (core module $libc
+    (memory (export "memory") 1)
+    (global $heap (mut i32) (i32.const 1024))
+    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+      (local $ptr i32)
+      ;; Align the top of the heap, the old allocations are never freed.
+      (local.set $ptr
+        (i32.and
+          (i32.add (global.get $heap) (i32.sub (local.get 2) (i32.const 1)))
+          (i32.sub (i32.const 0) (local.get 2))))
+      (global.set $heap (i32.add (local.get $ptr) (local.get 3)))
+      (local.get $ptr))
+  )
+  (core instance $libc (instantiate $libc))
+
+  (core func $fetch (canon lower (func $http "fetch")
+    (memory $libc "memory") (realloc (func $libc "realloc"))))
+
+  (core module $main
+    (import "libc" "memory" (memory 1))
+    (import "http" "fetch" (func $fetch (param i32 i32 i32 i32 i32 i32 i32 i32 i32)))
+    (data (i32.const 0) "test")
+    (data (i32.const 16) "invalid value")
+    (data (i32.const 32) "GET")
+    (data (i32.const 48) "http://127.0.0.1:9/")
+
+    ;; The results are returned at 512.
+    (func (export "kind") (result i32)
+      (i32.store (i32.const 512) (i32.const 0))
+      (i32.store (i32.const 516) (i32.const 4))
+      (i32.const 512))
+
+    (func (export "validate") (param $ptr i32) (param $len i32) (result i32)
+      (local $end i32)
+      (local.set $end (i32.add (local.get $ptr) (local.get $len)))
+      (i32.store8 (i32.const 512) (i32.const 0))
+      (block $done
+        (loop $next
+          (br_if $done (i32.ge_u (local.get $ptr) (local.get $end)))
+          (if (i32.eq (i32.load8_u (local.get $ptr)) (i32.const 33))
+            (then
+              (i32.store8 (i32.const 512) (i32.const 1))
+              (i32.store (i32.const 516) (i32.const 16))
+              (i32.store (i32.const 520) (i32.const 13))
+              (br $done)))
+          (local.set $ptr (i32.add (local.get $ptr) (i32.const 1)))
+          (br $next)))
+      (i32.const 512))
+
+    (func (export "new-address")
+      (param $ip i32) (param $ip_len i32) (param $port i32) (param $md i32) (param $md_len i32)
+      (result i32)
+      (i32.store8 (i32.const 512) (i32.const 0))
+      (if (i32.eq (local.get $port) (i32.const 1))
+        (then (loop $forever (br $forever))))
+      (if (i32.eq (local.get $port) (i32.const 2))
+        (then (drop (memory.grow (i32.const 64)))))
+      (if (i32.eq (local.get $port) (i32.const 3))
+        (then
+          (call $fetch
+            (i32.const 32) (i32.const 3)
+            (i32.const 48) (i32.const 19)
+            (i32.const 0) (i32.const 0)
+            (i32.const 0) (i32.const 0)
+            (i32.const 256))
+          (if (i32.load8_u (i32.const 256))
+            (then
+              (i32.store8 (i32.const 512) (i32.const 1))
+              (i32.store (i32.const 516) (i32.load (i32.const 260)))
+              (i32.store (i32.const 520) (i32.load (i32.const 264)))
+              (i32.store8 (i32.const 524) (i32.const 1))))))
+      (i32.const 512))
+  )
+  (core instance $main (instantiate $main
+    (with "libc" (instance $libc))
+    (with "http" (instance (export "fetch" (func $fetch))))
+  ))
+
+  (func (export "kind") (result string)
+    (canon lift (core func $main "kind") (memory $libc "memory")))
+  (func (export "validate") (param "metadata" string) (result (result (error string)))
+    (canon lift (core func $main "validate")
+      (memory $libc "memory") (realloc (func $libc "realloc"))))
+  (func (export "new-address")
+    (param "ip" string) (param "port" u16) (param "metadata" string)
+    (result (result (error $error)))
+    (canon lift (core func $main "new-address")
+      (memory $libc "memory") (realloc (func $libc "realloc"))))
+)